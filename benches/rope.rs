@@ -0,0 +1,114 @@
+//! Compares the rope that holds a buffer's lines with the `Vec<String>` it
+//! replaced, on what the editor does most: snapshotting for undo before an
+//! edit, inserting and removing lines, and reading lines. Run with
+//! `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../src/rope.rs"]
+mod rope;
+
+use rope::Rope;
+
+const LINES: usize = 100_000;
+
+/// Snapshots kept at once, as an undo history would.
+const HISTORY: usize = 100;
+
+/// Samples taken of each benchmark, each running it for about this long.
+const SAMPLES: usize = 20;
+const SAMPLE_TIME: Duration = Duration::from_millis(25);
+
+fn lines(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("line {}", i)).collect()
+}
+
+/// Runs `f` repeatedly and prints the median time of one run.
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up, and find how many runs fill a sample
+    let mut runs = 1;
+    loop {
+        let start = Instant::now();
+        for _ in 0..runs {
+            f();
+        }
+        if start.elapsed() >= SAMPLE_TIME {
+            break;
+        }
+        runs *= 2;
+    }
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..runs {
+                f();
+            }
+            start.elapsed() / runs
+        })
+        .collect();
+    samples.sort();
+    println!("{:<36}{:>12.1?}", name, samples[SAMPLES / 2]);
+}
+
+/// A fixed pseudo-random sequence of line numbers.
+fn line_numbers() -> impl FnMut() -> usize {
+    let mut seed: u64 = 7;
+    move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % LINES
+    }
+}
+
+fn main() {
+    println!("{} lines", LINES);
+
+    let mut vec = lines(LINES);
+    let mut history = Vec::new();
+    let mut next = line_numbers();
+    bench("vec: snapshot, then edit a line", || {
+        history.push(vec.clone());
+        if history.len() > HISTORY {
+            history.remove(0);
+        }
+        vec[next()].push('x');
+    });
+    drop(history);
+    let mut rope = Rope::from(lines(LINES));
+    let mut history = Vec::new();
+    let mut next = line_numbers();
+    bench("rope: snapshot, then edit a line", || {
+        history.push(rope.clone());
+        if history.len() > HISTORY {
+            history.remove(0);
+        }
+        rope[next()].push('x');
+    });
+    drop(history);
+
+    bench("vec: insert and remove a line", || {
+        vec.insert(LINES / 2, String::new());
+        vec.remove(LINES / 4);
+    });
+    bench("rope: insert and remove a line", || {
+        rope.insert(LINES / 2, String::new());
+        rope.remove(LINES / 4);
+    });
+
+    let mut next = line_numbers();
+    bench("vec: read a line by number", || {
+        black_box(vec[next()].len());
+    });
+    let mut next = line_numbers();
+    bench("rope: read a line by number", || {
+        black_box(rope[next()].len());
+    });
+
+    bench("vec: read every line in order", || {
+        black_box(vec.iter().map(|line| line.len()).sum::<usize>());
+    });
+    bench("rope: read every line in order", || {
+        black_box(rope.iter().map(|line| line.len()).sum::<usize>());
+    });
+}
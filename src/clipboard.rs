@@ -0,0 +1,68 @@
+//! System clipboard access through the platform's clipboard utilities.
+//!
+//! Each helper is tried in turn; if none is installed (e.g. over SSH with no
+//! display) the functions report failure and the editor keeps using its own
+//! register instead.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const COPY_COMMANDS: &[&[&str]] = &[
+    &["pbcopy"],
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard"],
+    &["xsel", "--clipboard", "--input"],
+    &["clip.exe"],
+];
+
+const PASTE_COMMANDS: &[&[&str]] = &[
+    &["pbpaste"],
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-o"],
+    &["xsel", "--clipboard", "--output"],
+    &["powershell.exe", "-NoProfile", "-Command", "Get-Clipboard"],
+];
+
+/// Copies `text` to the system clipboard. Returns whether a clipboard
+/// utility accepted it.
+pub fn set(text: &str) -> bool {
+    COPY_COMMANDS.iter().any(|cmd| copy_with(cmd, text))
+}
+
+/// Reads the system clipboard, or `None` if it is unavailable.
+pub fn get() -> Option<String> {
+    PASTE_COMMANDS.iter().find_map(|cmd| {
+        let output = Command::new(cmd[0])
+            .args(&cmd[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if output.status.success() {
+            Some(String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"))
+        } else {
+            None
+        }
+    })
+}
+
+fn copy_with(cmd: &[&str], text: &str) -> bool {
+    let child = Command::new(cmd[0])
+        .args(&cmd[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    let written = match child.stdin.take() {
+        // Dropping stdin closes it, so the child sees the end of its input
+        Some(mut stdin) => stdin.write_all(text.as_bytes()).is_ok(),
+        None => true,
+    };
+    // Waiting even after a failed write keeps the child from lingering as a zombie
+    let succeeded = child.wait().map(|status| status.success()).unwrap_or(false);
+    written && succeeded
+}
@@ -0,0 +1,504 @@
+//! User configuration read from `~/.config/text_editor/config.toml`.
+//!
+//! Only the small subset of TOML the options need is understood: `key =
+//! value` pairs with string, integer and boolean values, bare or quoted
+//! keys, `[section]` headers and `#` comments.
+
+use crate::keymap::{self, Keymap};
+use crossterm::style::Color;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub status_bar_fg: Color,
+    pub status_bar_bg: Color,
+    pub line_number_fg: Color,
+    pub line_number_bg: Color,
+    pub cursor_line_bg: Color,
+    pub trailing_whitespace_bg: Color,
+    pub fold_fg: Color,
+    pub fold_bg: Color,
+    pub color_column_bg: Color,
+    pub scrollbar_bg: Color,
+    pub scrollbar_thumb_bg: Color,
+    pub list_fg: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            status_bar_fg: Color::Black,
+            status_bar_bg: Color::White,
+            line_number_fg: Color::DarkGrey,
+            line_number_bg: Color::Black,
+            cursor_line_bg: Color::AnsiValue(236),
+            trailing_whitespace_bg: Color::DarkRed,
+            fold_fg: Color::Cyan,
+            fold_bg: Color::AnsiValue(237),
+            color_column_bg: Color::AnsiValue(235),
+            scrollbar_bg: Color::AnsiValue(236),
+            scrollbar_thumb_bg: Color::AnsiValue(244),
+            list_fg: Color::DarkGrey,
+        }
+    }
+}
+
+/// What `list` mode shows whitespace as; `None` leaves it blank.
+#[derive(Debug, Clone, Copy)]
+pub struct ListChars {
+    pub space: Option<char>,
+    /// Drawn in a tab's first cell, the rest left blank.
+    pub tab: Option<char>,
+    /// Drawn just past the end of each line.
+    pub eol: Option<char>,
+}
+
+impl Default for ListChars {
+    fn default() -> ListChars {
+        ListChars {
+            space: Some('·'),
+            tab: Some('→'),
+            eol: Some('¶'),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tab_width: usize,
+    pub expandtab: bool,
+    pub relative_numbers: bool,
+    pub line_numbers: bool,
+    pub clipboard: bool,
+    pub autoindent: bool,
+    pub autopairs: bool,
+    pub trim_trailing_whitespace: bool,
+    pub insert_final_newline: bool,
+    pub cursorline: bool,
+    pub highlight_trailing_whitespace: bool,
+    /// Keeps each file's undo history in the cache directory between
+    /// sessions. Off unless asked for, since that copies what the file held
+    /// at each step into the cache. Only undo is kept; redo starts empty.
+    pub undofile: bool,
+    pub regex: bool,
+    /// Column the ruler is drawn at, counting from 1; 0 draws none.
+    pub colorcolumn: usize,
+    /// Seconds without input before a modified file is written; 0 never.
+    pub autosave: usize,
+    pub scrollbar: bool,
+    /// Lines kept in view above and below the cursor.
+    pub scrolloff: usize,
+    /// Columns kept in view left and right of the cursor.
+    pub sidescrolloff: usize,
+    /// Shows spaces, tabs and line ends as `list_chars`.
+    pub list: bool,
+    pub list_chars: ListChars,
+    /// The key `<leader>` stands for in mappings, in key notation.
+    pub leader: String,
+    pub keymap: Keymap,
+    /// Snippet text by file type (`all` for any) and trigger word.
+    pub snippets: HashMap<(String, String), String>,
+    pub theme: Theme,
+    // `(mode, keys, replacement)` as read, built into `keymap` once parsed
+    mappings: Vec<(String, String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tab_width: 4,
+            expandtab: false,
+            relative_numbers: false,
+            line_numbers: true,
+            clipboard: false,
+            autoindent: true,
+            autopairs: false,
+            trim_trailing_whitespace: false,
+            insert_final_newline: true,
+            cursorline: false,
+            highlight_trailing_whitespace: false,
+            undofile: false,
+            regex: false,
+            colorcolumn: 80,
+            autosave: 0,
+            scrollbar: true,
+            scrolloff: 3,
+            sidescrolloff: 0,
+            list: false,
+            list_chars: ListChars::default(),
+            leader: "<Space>".to_string(),
+            keymap: Keymap::default(),
+            snippets: HashMap::new(),
+            theme: Theme::default(),
+            mappings: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl Config {
+    /// Loads the config file. A missing file gives the defaults. Settings
+    /// that cannot be used are left at their defaults and described in the
+    /// message returned with the rest.
+    pub fn load() -> (Config, Option<String>) {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return (Config::default(), None),
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let (config, errors) = Config::parse(&text);
+                let message = (!errors.is_empty())
+                    .then(|| format!("{}: {}", path.display(), errors.join("; ")));
+                (config, message)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Config::default(), None),
+            Err(e) => (Config::default(), Some(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    /// Parses config file `text`, returning what is wrong with any lines or
+    /// settings that were skipped, in line order.
+    pub fn parse(text: &str) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let (table, mut errors) = parse_table(text);
+        let mut entries: Vec<_> = table.into_iter().collect();
+        entries.sort_by_key(|(_, (line, _))| *line);
+        for (key, (line, value)) in entries {
+            if let Err(e) = config.apply(&key, value) {
+                errors.push((line, e));
+            }
+        }
+        errors.sort_by_key(|&(line, _)| line);
+        let mut errors: Vec<String> =
+            errors.into_iter().map(|(line, e)| format!("line {}: {}", line, e)).collect();
+        if let Err(e) = Keymap::new(&config.leader, &[]) {
+            errors.push(e);
+            config.leader = Config::default().leader;
+        }
+        // A bad mapping only loses itself
+        let mappings: Vec<_> = std::mem::take(&mut config.mappings)
+            .into_iter()
+            .filter(|mapping| match Keymap::new(&config.leader, std::slice::from_ref(mapping)) {
+                Ok(_) => true,
+                Err(e) => {
+                    errors.push(e);
+                    false
+                }
+            })
+            .collect();
+        config.keymap = Keymap::new(&config.leader, &mappings).unwrap_or_default();
+        config.mappings = mappings;
+        (config, errors)
+    }
+
+    fn apply(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "tab_width" => self.tab_width = positive(key, value)?,
+            "expandtab" => self.expandtab = boolean(key, value)?,
+            "relative_numbers" => self.relative_numbers = boolean(key, value)?,
+            "line_numbers" => self.line_numbers = boolean(key, value)?,
+            "clipboard" => self.clipboard = boolean(key, value)?,
+            "autoindent" => self.autoindent = boolean(key, value)?,
+            "autopairs" => self.autopairs = boolean(key, value)?,
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = boolean(key, value)?,
+            "insert_final_newline" => self.insert_final_newline = boolean(key, value)?,
+            "cursorline" => self.cursorline = boolean(key, value)?,
+            "highlight_trailing_whitespace" => {
+                self.highlight_trailing_whitespace = boolean(key, value)?
+            }
+            "undofile" => self.undofile = boolean(key, value)?,
+            "regex" => self.regex = boolean(key, value)?,
+            "colorcolumn" => self.colorcolumn = non_negative(key, value)?,
+            "autosave" => self.autosave = non_negative(key, value)?,
+            "scrollbar" => self.scrollbar = boolean(key, value)?,
+            "scrolloff" => self.scrolloff = non_negative(key, value)?,
+            "sidescrolloff" => self.sidescrolloff = non_negative(key, value)?,
+            "list" => self.list = boolean(key, value)?,
+            "list_chars.space" => self.list_chars.space = glyph(key, value)?,
+            "list_chars.tab" => self.list_chars.tab = glyph(key, value)?,
+            "list_chars.eol" => self.list_chars.eol = glyph(key, value)?,
+            "leader" => self.leader = string(key, value)?,
+            "colors.status_bar_fg" => self.theme.status_bar_fg = color(key, value)?,
+            "colors.status_bar_bg" => self.theme.status_bar_bg = color(key, value)?,
+            "colors.line_number_fg" => self.theme.line_number_fg = color(key, value)?,
+            "colors.line_number_bg" => self.theme.line_number_bg = color(key, value)?,
+            "colors.cursor_line_bg" => self.theme.cursor_line_bg = color(key, value)?,
+            "colors.trailing_whitespace_bg" => {
+                self.theme.trailing_whitespace_bg = color(key, value)?
+            }
+            "colors.fold_fg" => self.theme.fold_fg = color(key, value)?,
+            "colors.fold_bg" => self.theme.fold_bg = color(key, value)?,
+            "colors.color_column_bg" => self.theme.color_column_bg = color(key, value)?,
+            "colors.scrollbar_bg" => self.theme.scrollbar_bg = color(key, value)?,
+            "colors.scrollbar_thumb_bg" => self.theme.scrollbar_thumb_bg = color(key, value)?,
+            "colors.list_fg" => self.theme.list_fg = color(key, value)?,
+            _ => match key.strip_prefix("keymap.").and_then(|rest| rest.split_once('.')) {
+                Some((mode, keys)) if keymap::MODES.contains(&mode) => {
+                    let Value::Str(replacement) = value else {
+                        return Err(format!("`{}` must be a string of keys", key));
+                    };
+                    self.mappings.push((mode.to_string(), keys.to_string(), replacement));
+                }
+                Some((mode, _)) => return Err(format!("unknown keymap mode `{}`", mode)),
+                None => match key.strip_prefix("snippets.").and_then(|rest| rest.split_once('.')) {
+                    Some((filetype, trigger)) => {
+                        let body = string(key, value)?;
+                        self.snippets.insert((filetype.to_string(), trigger.to_string()), body);
+                    }
+                    None => return Err(format!("unknown option `{}`", key)),
+                },
+            },
+        }
+        Ok(())
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("text_editor").join("config.toml"))
+}
+
+/// Dotted keys (`section.key`) mapped to their line number and value.
+type Table = HashMap<String, (usize, Value)>;
+
+/// Parses `text` into a table, along with the lines that could not be read
+/// and why.
+fn parse_table(text: &str) -> (Table, Vec<(usize, String)>) {
+    let mut table = HashMap::new();
+    let mut errors = Vec::new();
+    let mut section = String::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        // A quoted key may hold characters a bare one can't, like `<` or `=`
+        let quoted = line
+            .strip_prefix('"')
+            .and_then(|rest| rest.split_once('"'))
+            .and_then(|(key, rest)| Some((key, rest.trim_start().strip_prefix('=')?)));
+        let parts = quoted.or_else(|| line.split_once('=').map(|(key, value)| (key.trim(), value)));
+        let Some((key, value)) = parts else {
+            errors.push((i + 1, "expected `key = value`".to_string()));
+            continue;
+        };
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", section, key)
+        };
+        match parse_value(value.trim()) {
+            Ok(value) => {
+                table.insert(key, (i + 1, value));
+            }
+            Err(e) => errors.push((i + 1, e)),
+        }
+    }
+    (table, errors)
+}
+
+/// Drops a trailing `#` comment, leaving `#` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // Whatever a backslash escapes can't end the string
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    match text {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return Ok(Value::Str(unescape(inner)));
+    }
+    text.replace('_', "")
+        .parse()
+        .map(Value::Int)
+        .map_err(|_| format!("invalid value `{}`", text))
+}
+
+/// Resolves the `\"`, `\\`, `\n` and `\t` escapes of a string; other
+/// backslashes are kept as written.
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c @ ('"' | '\\')) => out.push(c),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn boolean(key: &str, value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        _ => Err(format!("`{}` must be true or false", key)),
+    }
+}
+
+fn positive(key: &str, value: Value) -> Result<usize, String> {
+    match value {
+        Value::Int(n) if n > 0 => Ok(n as usize),
+        _ => Err(format!("`{}` must be a positive integer", key)),
+    }
+}
+
+fn non_negative(key: &str, value: Value) -> Result<usize, String> {
+    match value {
+        Value::Int(n) if n >= 0 => Ok(n as usize),
+        _ => Err(format!("`{}` must be a non-negative integer", key)),
+    }
+}
+
+fn string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::Str(text) => Ok(text),
+        _ => Err(format!("`{}` must be a string", key)),
+    }
+}
+
+/// A single character, or none for the empty string.
+fn glyph(key: &str, value: Value) -> Result<Option<char>, String> {
+    let text = string(key, value)?;
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (glyph, None) => Ok(glyph),
+        _ => Err(format!("`{}` must be a single character or empty", key)),
+    }
+}
+
+fn color(key: &str, value: Value) -> Result<Color, String> {
+    let name = match value {
+        Value::Str(name) => name,
+        _ => return Err(format!("`{}` must be a color string", key)),
+    };
+    parse_color(&name).ok_or_else(|| format!("unknown color `{}`", name))
+}
+
+/// Accepts crossterm's color names (`dark_grey`, `blue`, ...) and `#rrggbb`.
+fn parse_color(name: &str) -> Option<Color> {
+    if let Some(hex) = name.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+    let color = match name.to_lowercase().replace(['-', ' '], "_").as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "dark_grey" | "dark_gray" => Color::DarkGrey,
+        "red" => Color::Red,
+        "dark_red" => Color::DarkRed,
+        "green" => Color::Green,
+        "dark_green" => Color::DarkGreen,
+        "yellow" => Color::Yellow,
+        "dark_yellow" => Color::DarkYellow,
+        "blue" => Color::Blue,
+        "dark_blue" => Color::DarkBlue,
+        "magenta" => Color::Magenta,
+        "dark_magenta" => Color::DarkMagenta,
+        "cyan" => Color::Cyan,
+        "dark_cyan" => Color::DarkCyan,
+        "white" => Color::White,
+        "grey" | "gray" => Color::Grey,
+        _ => return None,
+    };
+    Some(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_inside_a_string_is_not_a_comment() {
+        let config = Config::parse("leader = \"#\" # comment\n").0;
+        assert_eq!(config.leader, "#");
+    }
+
+    #[test]
+    fn comment_after_an_escaped_backslash_is_stripped() {
+        assert_eq!(strip_comment(r#"path = "C:\\" # comment"#), r#"path = "C:\\" "#);
+        let config = Config::parse("[snippets.all]\ndir = \"C:\\\\\" # comment\n").0;
+        let body = &config.snippets[&("all".to_string(), "dir".to_string())];
+        assert_eq!(body, "C:\\");
+    }
+
+    #[test]
+    fn escaped_quote_does_not_end_the_string() {
+        let config = Config::parse("[snippets.all]\nq = \"say \\\"#\\\"\" # comment\n").0;
+        let body = &config.snippets[&("all".to_string(), "q".to_string())];
+        assert_eq!(body, "say \"#\"");
+    }
+
+    #[test]
+    fn bad_settings_are_skipped_and_the_rest_applied() {
+        let text = "tab_width = 8\ntab_widht = 2\nexpandtab = 3\nnonsense\nlist = true\n\
+                    [keymap.normal]\n\"<Nope>\" = \"x\"\n\"Q\" = \"dd\"\n";
+        let (config, errors) = Config::parse(text);
+        assert_eq!(config.tab_width, 8);
+        assert!(config.list);
+        assert!(!config.expandtab);
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors[0].starts_with("line 2: unknown option `tab_widht`"));
+        assert!(errors[1].starts_with("line 3:"));
+        assert!(errors[2].starts_with("line 4: expected"));
+        assert_eq!(errors[3], "unknown key `<Nope>`");
+        assert!(config.keymap.is_prefix("normal", &[]));
+    }
+
+    #[test]
+    fn a_bad_leader_falls_back_to_the_default() {
+        let (config, errors) = Config::parse("leader = \"ab\"\n");
+        assert_eq!(config.leader, "<Space>");
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,76 @@
+//! File types, told apart by file name and extension, and the settings
+//! that differ between them: comment marker, indentation and whether braces
+//! open blocks.
+//!
+//! A file type is a short lowercase name such as `rust` or `python`, as
+//! vim uses. The empty name means no known type.
+
+use std::path::Path;
+
+/// The file type of `path`, or `""` if it isn't recognized.
+pub fn detect(path: &Path) -> &'static str {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    match name {
+        "Makefile" | "makefile" | "GNUmakefile" => return "make",
+        ".bashrc" | ".bash_profile" | ".zshrc" | ".profile" => return "sh",
+        _ => {}
+    }
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match ext {
+        "rs" => "rust",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "cs" => "cs",
+        "go" => "go",
+        "java" => "java",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "json" => "json",
+        "py" => "python",
+        "rb" => "ruby",
+        "pl" | "pm" => "perl",
+        "sh" | "bash" | "zsh" => "sh",
+        "lua" => "lua",
+        "sql" => "sql",
+        "hs" => "haskell",
+        "vim" => "vim",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "conf" => "conf",
+        "md" | "markdown" => "markdown",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "mk" => "make",
+        _ => "",
+    }
+}
+
+/// The line comment marker for `filetype`, `//` if it has none of its own.
+pub fn comment_prefix(filetype: &str) -> &'static str {
+    match filetype {
+        "python" | "sh" | "ruby" | "perl" | "toml" | "yaml" | "conf" | "make" => "#",
+        "lua" | "sql" | "haskell" => "--",
+        "vim" => "\"",
+        _ => "//",
+    }
+}
+
+/// Whether `filetype` uses braces for blocks, so `{` opens an indent level.
+pub fn uses_braces(filetype: &str) -> bool {
+    matches!(
+        filetype,
+        "rust" | "c" | "cpp" | "java" | "javascript" | "typescript" | "go" | "cs" | "css" | "json"
+    )
+}
+
+/// The indentation `filetype` conventionally uses, as a tab width and
+/// whether to indent with spaces, or `None` to keep the configured one.
+pub fn indent(filetype: &str) -> Option<(usize, bool)> {
+    match filetype {
+        "rust" | "python" => Some((4, true)),
+        "javascript" | "typescript" | "json" | "yaml" | "html" | "css" => Some((2, true)),
+        "go" => Some((4, false)),
+        "make" => Some((8, false)),
+        _ => None,
+    }
+}
@@ -0,0 +1,95 @@
+//! Fuzzy matching for the pickers, and the file list `:find` picks from.
+//!
+//! A query matches a candidate when its characters appear in order,
+//! ignoring case. Matches score higher when they run together, start a
+//! word or path component, or come in a shorter candidate, roughly as fzf
+//! and similar finders rank them.
+
+use std::fs;
+use std::path::Path;
+
+/// Directories deeper than this below the root aren't searched.
+const MAX_DEPTH: usize = 12;
+
+/// Build output and dependency directories, which are rarely what's wanted.
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules"];
+
+/// The walk stops after this many files, so a huge tree can't hang it.
+const MAX_FILES: usize = 50_000;
+
+/// Files below `root`, as paths relative to it, sorted. Hidden directories
+/// such as `.git` and the `SKIPPED_DIRS` aren't entered.
+pub fn files(root: &Path) -> Vec<String> {
+    let mut out = Vec::new();
+    walk(root, "", 0, &mut out);
+    out.sort();
+    out
+}
+
+fn walk(dir: &Path, prefix: &str, depth: usize, out: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if out.len() >= MAX_FILES {
+            return;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{}{}", prefix, name);
+        let Ok(mut kind) = entry.file_type() else { continue };
+        // Links are listed as what they point to; broken ones not at all
+        if kind.is_symlink() {
+            let Ok(target) = fs::metadata(entry.path()) else { continue };
+            kind = target.file_type();
+        }
+        if kind.is_dir() {
+            let skipped = name.starts_with('.') || SKIPPED_DIRS.contains(&name.as_str());
+            if !skipped && depth < MAX_DEPTH {
+                walk(&entry.path(), &format!("{}/", path), depth + 1, out);
+            }
+        } else {
+            out.push(path);
+        }
+    }
+}
+
+/// How well `query` matches `candidate`, higher being better, or `None`
+/// if it doesn't match at all. The empty query matches everything.
+pub fn score(query: &str, candidate: &str) -> Option<i64> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut score = 0;
+    let mut last: Option<usize> = None;
+    let mut i = 0;
+    for q in query.chars().filter(|c| !c.is_whitespace()) {
+        let q = q.to_lowercase().next().unwrap_or(q);
+        let found = (i..chars.len()).find(|&j| chars[j].to_lowercase().next() == Some(q))?;
+        score += 10;
+        match last {
+            Some(last) if found == last + 1 => score += 15,
+            Some(last) => score -= std::cmp::min(found - last - 1, 10) as i64,
+            None => score -= std::cmp::min(found, 10) as i64,
+        }
+        let boundary = found == 0 || matches!(chars[found - 1], '/' | '_' | '-' | '.' | ' ');
+        if boundary {
+            score += 10;
+        }
+        last = Some(found);
+        i = found + 1;
+    }
+    Some(score - chars.len() as i64 / 4)
+}
+
+/// The indices of the `candidates` matching `query`, best first.
+pub fn filter(query: &str, candidates: &[String]) -> Vec<usize> {
+    if query.trim().is_empty() {
+        return (0..candidates.len()).collect();
+    }
+    let mut scored: Vec<(i64, usize)> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, candidate)| Some((score(query, candidate)?, i)))
+        .collect();
+    // A stable sort keeps equal scores in the candidates' own order
+    scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
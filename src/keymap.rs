@@ -0,0 +1,171 @@
+//! Key remapping: sequences typed in a mode stand for other keys, set in the
+//! config file under `[keymap.<mode>]`.
+//!
+//! Keys are written in vim's notation, with `<Esc>`, `<CR>`, `<C-x>` and
+//! the like for the ones that aren't plain characters, and `<leader>` for
+//! the configured leader key. A mapping's keys run as typed, without being
+//! mapped again.
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// The modes a mapping can be defined for.
+pub const MODES: &[&str] = &["normal", "insert", "visual", "command"];
+
+#[derive(Debug, Clone)]
+struct Mapping {
+    mode: String,
+    lhs: Vec<KeyEvent>,
+    rhs: Vec<KeyEvent>,
+    // As written in the config, to show what a key does
+    description: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    mappings: Vec<Mapping>,
+}
+
+impl Keymap {
+    /// Builds the keymap from `(mode, keys, replacement)` triples, with
+    /// `<leader>` standing for the `leader` key.
+    pub fn new(leader: &str, mappings: &[(String, String, String)]) -> Result<Keymap, String> {
+        let leader = match parse_keys(leader, None)?.as_slice() {
+            &[key] => key,
+            _ => return Err(format!("`leader` must be a single key, not `{}`", leader)),
+        };
+        let mut keymap = Keymap::default();
+        for (mode, lhs, rhs) in mappings {
+            if !MODES.contains(&mode.as_str()) {
+                return Err(format!("unknown keymap mode `{}`", mode));
+            }
+            let lhs_keys = parse_keys(lhs, Some(leader))?;
+            if lhs_keys.is_empty() {
+                return Err(format!("empty key sequence in keymap.{}", mode));
+            }
+            keymap.mappings.push(Mapping {
+                mode: mode.clone(),
+                lhs: lhs_keys,
+                rhs: parse_keys(rhs, Some(leader))?,
+                description: rhs.clone(),
+            });
+        }
+        Ok(keymap)
+    }
+
+    /// Whether `keys` begin some longer mapping in `mode`, so more keys
+    /// should be waited for.
+    pub fn is_prefix(&self, mode: &str, keys: &[KeyEvent]) -> bool {
+        self.in_mode(mode)
+            .any(|mapping| mapping.lhs.len() > keys.len() && mapping.lhs.starts_with(keys))
+    }
+
+    /// The longest mapping in `mode` that `keys` start with: the number of
+    /// keys it covers and the keys they stand for.
+    pub fn longest_match<'a>(
+        &'a self,
+        mode: &'a str,
+        keys: &[KeyEvent],
+    ) -> Option<(usize, &'a [KeyEvent])> {
+        self.in_mode(mode)
+            .filter(|mapping| keys.starts_with(&mapping.lhs))
+            .max_by_key(|mapping| mapping.lhs.len())
+            .map(|mapping| (mapping.lhs.len(), mapping.rhs.as_slice()))
+    }
+
+    /// The mappings in `mode` that `keys` begin, as the rest of their keys
+    /// and what they do.
+    pub fn continuations(&self, mode: &str, keys: &[KeyEvent]) -> Vec<(String, String)> {
+        let mut continuations: Vec<(String, String)> = self
+            .in_mode(mode)
+            .filter(|mapping| mapping.lhs.len() > keys.len() && mapping.lhs.starts_with(keys))
+            .map(|mapping| {
+                let rest = mapping.lhs[keys.len()..].iter().map(|&key| key_name(key)).collect();
+                (rest, mapping.description.clone())
+            })
+            .collect();
+        continuations.sort();
+        continuations
+    }
+
+    fn in_mode<'a>(&'a self, mode: &'a str) -> impl Iterator<Item = &'a Mapping> {
+        self.mappings.iter().filter(move |mapping| mapping.mode == mode)
+    }
+}
+
+/// Parses keys written in vim's notation. Without a `leader`, `<leader>`
+/// is an error.
+pub fn parse_keys(notation: &str, leader: Option<KeyEvent>) -> Result<Vec<KeyEvent>, String> {
+    let mut keys = Vec::new();
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        let special = rest
+            .strip_prefix('<')
+            .and_then(|after| after.split_once('>'))
+            .filter(|(name, _)| !name.is_empty() && !name.contains('<'));
+        let Some((name, after)) = special else {
+            keys.push(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        let key = match name.to_ascii_lowercase().as_str() {
+            "leader" => leader.ok_or("`<leader>` can't be used here")?,
+            lower => named_key(lower).ok_or_else(|| format!("unknown key `<{}>`", name))?,
+        };
+        keys.push(key);
+        rest = after;
+    }
+    Ok(keys)
+}
+
+/// The key called `name` (lowercase) between angle brackets.
+fn named_key(name: &str) -> Option<KeyEvent> {
+    let code = match name {
+        "esc" => KeyCode::Esc,
+        "cr" | "enter" | "return" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "bs" | "backspace" => KeyCode::Backspace,
+        "del" => KeyCode::Delete,
+        "space" => KeyCode::Char(' '),
+        "lt" => KeyCode::Char('<'),
+        "bar" => KeyCode::Char('|'),
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        _ => {
+            let c = name.strip_prefix("c-").filter(|c| c.chars().count() == 1)?;
+            let c = c.chars().next()?;
+            return Some(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL));
+        }
+    };
+    Some(KeyEvent::new(code, KeyModifiers::NONE))
+}
+
+/// `key` in the notation `parse_keys` reads.
+pub fn key_name(key: KeyEvent) -> String {
+    let name = match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => return format!("<C-{}>", c),
+        KeyCode::Char(' ') => "Space",
+        KeyCode::Char('<') => "lt",
+        KeyCode::Char(c) => return c.to_string(),
+        KeyCode::Esc => "Esc",
+        KeyCode::Enter => "CR",
+        KeyCode::Tab => "Tab",
+        KeyCode::Backspace => "BS",
+        KeyCode::Delete => "Del",
+        KeyCode::Up => "Up",
+        KeyCode::Down => "Down",
+        KeyCode::Left => "Left",
+        KeyCode::Right => "Right",
+        KeyCode::Home => "Home",
+        KeyCode::End => "End",
+        KeyCode::PageUp => "PageUp",
+        KeyCode::PageDown => "PageDown",
+        _ => "?",
+    };
+    format!("<{}>", name)
+}
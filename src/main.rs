@@ -1,237 +1,5567 @@
+mod clipboard;
+mod config;
+mod filetype;
+mod finder;
+mod keymap;
+mod positions;
+mod regex;
+mod rope;
+mod session;
+mod snippet;
+mod swapfile;
+mod syntax;
+mod undofile;
+mod unicode;
+
+use config::{Config, ListChars, Theme};
+use keymap::Keymap;
+use regex::Regex;
+use rope::Rope;
+use session::Session;
+
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+        MouseButton, MouseEvent, MouseEventKind,
+    },
     execute, queue,
-    style::{self, Color, Colors, Stylize},
+    style::{self, Attribute, Color, Colors},
     terminal::{self, ClearType},
 };
-use std::io::{stdout, Write};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, stdout, Read, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
 #[derive(Debug)]
 enum Mode {
     Normal,
     Insert,
+    Replace,
+    Command,
+    Search,
+    Visual,
+    VisualLine,
+    VisualBlock,
 }
 
-struct Editor {
-    mode: Mode,
-    content: Vec<String>,
+/// An inclusive, ordered range of text selected in visual mode.
+#[derive(Debug, Clone, Copy)]
+struct Selection {
+    start: (usize, usize),
+    end: (usize, usize),
+    linewise: bool,
+    // A rectangle: columns `start.1..=end.1` of every line in between
+    block: bool,
+}
+
+impl Selection {
+    /// The selected character columns on line `y` as a half-open range.
+    fn columns(&self, y: usize, line_len: usize) -> Option<(usize, usize)> {
+        if y < self.start.0 || y > self.end.0 {
+            return None;
+        }
+        if self.linewise {
+            return Some((0, line_len));
+        }
+        if self.block {
+            let (start, end) = (self.start.1, self.end.1 + 1);
+            return Some((std::cmp::min(start, line_len), std::cmp::min(end, line_len)));
+        }
+        let start = if y == self.start.0 { self.start.1 } else { 0 };
+        let end = if y == self.end.0 { self.end.1 + 1 } else { line_len };
+        Some((std::cmp::min(start, line_len), std::cmp::min(end, line_len)))
+    }
+}
+
+/// Lines `start..=end` folded with `zf`, shown as a single line while
+/// closed. Folds may nest.
+#[derive(Debug, Clone, Copy)]
+struct Fold {
+    start: usize,
+    end: usize,
+    closed: bool,
+}
+
+/// Yanked or deleted text. Linewise registers paste as whole lines.
+#[derive(Debug, Clone, Default)]
+struct Register {
+    text: Vec<String>,
+    linewise: bool,
+}
+
+/// Where text typed after `I` or `A` in block visual mode gets copied to.
+#[derive(Debug, Clone, Copy)]
+struct BlockInsert {
+    first: usize,
+    last: usize,
+    x: usize,
+    // Length of the first line before typing, to find what was inserted
+    line_len: usize,
+    append: bool,
+}
+
+/// Words offered by Ctrl-N and Ctrl-P in insert mode for the word typed
+/// so far, which starts at column `start` of the cursor line.
+struct Completion {
+    start: usize,
+    prefix: String,
+    words: Vec<String>,
+    // Word in the buffer now, `None` while showing the typed prefix
+    index: Option<usize>,
+}
+
+/// A list to choose from by typing part of an entry, drawn over the
+/// windows while `pick` runs.
+struct Picker {
+    title: String,
+    query: String,
+    items: Vec<String>,
+    // Indices into `items` of those matching `query`, best first
+    matches: Vec<usize>,
+    // Index into `matches`
+    selected: usize,
+}
+
+/// A snapshot of the buffer taken before a change, for undo and redo.
+struct UndoState {
+    content: Rope,
+    cursor_x: usize,
+    cursor_y: usize,
+    // Whether this differs from the file on disk, so that undoing back to
+    // what was saved leaves the buffer unmodified
+    modified: bool,
+}
+
+/// How lines are terminated in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        }
+    }
+}
+
+/// A file being edited, with its own cursor, view, undo history and marks.
+struct Buffer {
+    content: Rope,
     cursor_x: usize,
     cursor_y: usize,
     filename: Option<PathBuf>,
-    terminal_size: (u16, u16),
+    modified: bool,
+    // Written back on save; lines in `content` never hold the `\r`
+    line_ending: LineEnding,
+    // Detected from the file name unless set with `:set filetype`
+    filetype: String,
+    // The file type's own indentation, over the editor-wide settings
+    tab_width: Option<usize>,
+    expandtab: Option<bool>,
+    // Whether `:set` chose those for this buffer, so a file type doesn't
+    tab_width_set: bool,
+    expandtab_set: bool,
+    // Modification time and size of the file when last read or written
+    disk_stamp: Option<(SystemTime, u64)>,
+    // Only the start of a file too large to load was read, so writing it
+    // back would lose the rest; it can only be written to another file
+    partial: bool,
+    // The swap file this session keeps the buffer's changes in, once one is
+    // written. Swap files found on opening are left alone, so this is
+    // another name while one of those is around.
+    swap_file: Option<PathBuf>,
+    undo_stack: Vec<UndoState>,
+    redo_stack: Vec<UndoState>,
+    row_offset: usize,
+    col_offset: usize,
+    // Positions set with `m`, kept on the same text as lines come and go
+    marks: HashMap<char, (usize, usize)>,
+    folds: Vec<Fold>,
+    // Words and characters counted at a `change_tick`, kept so counting a
+    // large buffer again before it changes is free
+    word_count: Option<(usize, usize, usize)>,
 }
 
-impl Editor {
-    fn new() -> Editor {
-        Editor {
-            mode: Mode::Normal,
-            content: vec![String::new()],
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Buffer {
+            content: Rope::from(vec![String::new()]),
             cursor_x: 0,
             cursor_y: 0,
             filename: None,
-            terminal_size: terminal::size().unwrap_or((80, 24)),
+            modified: false,
+            line_ending: LineEnding::Unix,
+            filetype: String::new(),
+            tab_width: None,
+            expandtab: None,
+            tab_width_set: false,
+            expandtab_set: false,
+            disk_stamp: None,
+            partial: false,
+            swap_file: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            row_offset: 0,
+            col_offset: 0,
+            marks: HashMap::new(),
+            folds: Vec::new(),
+            word_count: None,
         }
     }
+}
 
-    fn run(&mut self) -> crossterm::Result<()> {
-        terminal::enable_raw_mode()?;
-        execute!(stdout(), terminal::EnterAlternateScreen)?;
+/// A view onto a buffer. The active window's cursor and scroll offsets live
+/// in its buffer; the others keep their own here.
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    buffer: usize,
+    cursor_x: usize,
+    cursor_y: usize,
+    row_offset: usize,
+    col_offset: usize,
+}
 
-        loop {
-            self.terminal_size = terminal::size()?;
-            self.refresh_screen()?;
+/// A region of the screen, in cells.
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
 
-            if let Event::Key(event) = event::read()? {
-                if let Err(_) = self.handle_keypress(event) {
-                    break;
+/// Lines scrolled per mouse wheel notch.
+const WHEEL_SCROLL_LINES: usize = 3;
+
+/// Files larger than this are only read up to here, and then read-only.
+const LARGE_FILE_BYTES: u64 = 128 * 1024 * 1024;
+
+/// Pause in typing after which unsaved changes go to the swap file.
+const SWAP_DELAY: Duration = Duration::from_secs(4);
+
+/// How long to wait for the next key of a mapping before taking the keys
+/// typed so far as they are.
+const MAPPING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pause in the middle of a key sequence before listing what can follow.
+const WHICH_KEY_DELAY: Duration = Duration::from_millis(500);
+
+/// Oldest undo steps are dropped beyond this many.
+const UNDO_LIMIT: usize = 1000;
+
+/// Oldest jump list entries are dropped beyond this many, as in vim.
+const JUMP_LIMIT: usize = 100;
+
+/// A `(line, column)` position in the buffer, in characters.
+type Pos = (usize, usize);
+
+/// The first and last line an ex command applies to.
+type LineRange = (usize, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Blank,
+    Word,
+    Punct,
+    EmptyLine,
+}
+
+struct Editor {
+    mode: Mode,
+    // The active buffer. Its slot in `buffers` holds an empty placeholder
+    // until another buffer is switched in.
+    buffer: Buffer,
+    buffers: Vec<Buffer>,
+    current: usize,
+    windows: Vec<Window>,
+    current_window: usize,
+    // Windows sit side by side after `:vsplit`, stacked after `:split`
+    split_vertical: bool,
+    terminal_size: (u16, u16),
+    status_message: Option<String>,
+    quit_pending: bool,
+    // Set when the user quits, so the loop ending is a clean exit rather
+    // than an error
+    exiting: bool,
+    tab_width: usize,
+    expandtab: bool,
+    autoindent: bool,
+    autopairs: bool,
+    trim_trailing_whitespace: bool,
+    insert_final_newline: bool,
+    cursorline: bool,
+    highlight_trailing_whitespace: bool,
+    // Keep undo history across sessions, see `undofile`
+    undofile: bool,
+    // Treat search and substitute patterns as regular expressions
+    regex: bool,
+    // Column of the ruler, 0 for none
+    colorcolumn: usize,
+    // Seconds idle before the file is written, 0 for never
+    autosave: usize,
+    scrollbar: bool,
+    list: bool,
+    list_chars: ListChars,
+    scrolloff: usize,
+    sidescrolloff: usize,
+    // Set while the mouse button pressed on a scrollbar is held
+    dragging_scrollbar: bool,
+    keymap: Keymap,
+    // Keys typed that may still turn out to start a mapping
+    pending_map: Vec<KeyEvent>,
+    // Whether the popup of keys that can follow is showing
+    which_key: bool,
+    snippets: HashMap<(String, String), String>,
+    // Tab stops of expanded snippets still to visit, as line and character
+    // column, moved along as text is typed or deleted before them
+    snippet_stops: Vec<(usize, usize)>,
+    picker: Option<Picker>,
+    // Keys typed so far of a multi-key command such as `dd` or `gcc`
+    pending_keys: String,
+    count: Option<usize>,
+    command_buffer: String,
+    last_search: Option<String>,
+    highlight_search: bool,
+    // Set once the current insert session has its undo snapshot
+    undo_group_open: bool,
+    visual_anchor: (usize, usize),
+    // Registers by name, with the unnamed one under `"`
+    registers: HashMap<char, Register>,
+    // Register picked with a `"x` prefix for the next yank or paste
+    selected_register: Option<char>,
+    // Route every yank and paste through the system clipboard
+    clipboard: bool,
+    relative_numbers: bool,
+    show_line_numbers: bool,
+    theme: Theme,
+    // Bumped on every undoable change, to tell which commands edited text
+    change_tick: usize,
+    change_start_tick: usize,
+    // Keys of the command being typed, and of the last one that changed text
+    change_keys: Vec<KeyEvent>,
+    last_change: Vec<KeyEvent>,
+    macros: HashMap<char, Vec<KeyEvent>>,
+    // Register and keys of the macro being recorded with `q`
+    recording: Option<(char, Vec<KeyEvent>)>,
+    last_macro: Option<char>,
+    // The last `f`, `t`, `F` or `T` and its character, for `;` and `,`
+    last_find: Option<(char, char)>,
+    // Set by `I` or `A` in block visual mode until the insert ends
+    block_insert: Option<BlockInsert>,
+    // Characters overwritten in replace mode, `None` where the line grew
+    replaced: Vec<Option<char>>,
+    // Registers of the macros currently replaying, innermost last
+    playing: Vec<char>,
+    completion: Option<Completion>,
+    // Line and character range of the match `:s///c` is asking about
+    confirm_match: Option<(usize, usize, usize)>,
+    // Buffer, line and column before each jump, for Ctrl-O and Ctrl-I
+    jumps: Vec<(usize, usize, usize)>,
+    // Entry Ctrl-O and Ctrl-I last went to, or `jumps.len()` if none
+    jump_index: usize,
+}
+
+impl Editor {
+    fn new(config: Config) -> Editor {
+        Editor {
+            mode: Mode::Normal,
+            buffer: Buffer::default(),
+            buffers: vec![Buffer::default()],
+            current: 0,
+            windows: vec![Window::default()],
+            current_window: 0,
+            split_vertical: false,
+            terminal_size: terminal::size().unwrap_or((80, 24)),
+            status_message: None,
+            quit_pending: false,
+            exiting: false,
+            tab_width: config.tab_width,
+            expandtab: config.expandtab,
+            autoindent: config.autoindent,
+            autopairs: config.autopairs,
+            trim_trailing_whitespace: config.trim_trailing_whitespace,
+            insert_final_newline: config.insert_final_newline,
+            cursorline: config.cursorline,
+            highlight_trailing_whitespace: config.highlight_trailing_whitespace,
+            undofile: config.undofile,
+            regex: config.regex,
+            colorcolumn: config.colorcolumn,
+            autosave: config.autosave,
+            scrollbar: config.scrollbar,
+            list: config.list,
+            list_chars: config.list_chars,
+            scrolloff: config.scrolloff,
+            sidescrolloff: config.sidescrolloff,
+            dragging_scrollbar: false,
+            keymap: config.keymap.clone(),
+            pending_map: Vec::new(),
+            which_key: false,
+            snippets: config.snippets.clone(),
+            snippet_stops: Vec::new(),
+            picker: None,
+            pending_keys: String::new(),
+            count: None,
+            command_buffer: String::new(),
+            last_search: None,
+            highlight_search: false,
+            undo_group_open: false,
+            visual_anchor: (0, 0),
+            registers: HashMap::new(),
+            selected_register: None,
+            clipboard: config.clipboard,
+            relative_numbers: config.relative_numbers,
+            show_line_numbers: config.line_numbers,
+            theme: config.theme,
+            change_tick: 0,
+            change_start_tick: 0,
+            change_keys: Vec::new(),
+            last_change: Vec::new(),
+            macros: HashMap::new(),
+            recording: None,
+            last_macro: None,
+            last_find: None,
+            block_insert: None,
+            replaced: Vec::new(),
+            playing: Vec::new(),
+            completion: None,
+            confirm_match: None,
+            jumps: Vec::new(),
+            jump_index: 0,
+        }
+    }
+
+    fn open(&mut self, path: PathBuf) -> io::Result<()> {
+        match read_text(&path) {
+            Ok((text, partial)) => {
+                // The more common ending wins; `lines` strips `\r\n` either way
+                let dos = text.matches("\r\n").count();
+                let unix = text.matches('\n').count() - dos;
+                self.buffer.line_ending = if dos > unix { LineEnding::Dos } else { LineEnding::Unix };
+                self.buffer.content = split_lines(&text).into();
+                self.buffer.disk_stamp = disk_stamp(&path);
+                self.buffer.partial = partial;
+                if !partial && swapfile::exists(&path) {
+                    self.status_message = Some(format!(
+                        "Found a swap file for \"{}\": :recover restores its changes, :recover! deletes it",
+                        path.display()
+                    ));
+                } else if partial {
+                    self.status_message = Some(format!(
+                        "\"{}\" is too large; showing the first {} lines, which only :w {{file}} can write",
+                        path.display(),
+                        self.buffer.content.len()
+                    ));
+                }
+                let history = if self.undofile && !partial {
+                    undofile::load(&path, &text)
+                } else {
+                    None
+                };
+                if let Some(states) = history {
+                    self.buffer.undo_stack = states;
                 }
             }
+            // A missing file starts out empty; saving will create it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
         }
+        self.set_filetype(filetype::detect(&path));
+        self.buffer.filename = Some(path);
+        Ok(())
+    }
 
-        execute!(stdout(), terminal::LeaveAlternateScreen)?;
-        terminal::disable_raw_mode()?;
+    /// Reads the file again, dropping unsaved changes. They stay one undo
+    /// step away unless the file's own undo history replaces them.
+    fn reload(&mut self) {
+        let Some(path) = self.buffer.filename.clone() else {
+            self.status_message = Some("No file name".to_string());
+            return;
+        };
+        if !path.exists() {
+            self.status_message = Some(format!("Can't reload \"{}\": not found", path.display()));
+            return;
+        }
+        // The changes being dropped shouldn't be offered for recovery
+        self.remove_swap();
+        self.push_undo(self.snapshot());
+        if let Err(e) = self.open(path) {
+            self.status_message = Some(format!("Error reading file: {}", e));
+            return;
+        }
+        self.mark_saved();
+        self.buffer.cursor_y = std::cmp::min(self.buffer.cursor_y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(self.buffer.cursor_y));
+        if self.buffer.partial {
+            return;
+        }
+        self.status_message = Some(format!(
+            "\"{}\" {} lines",
+            self.display_name(),
+            self.buffer.content.len()
+        ));
+    }
+
+    /// Opens `path` in a buffer of its own, or switches to it if it is
+    /// already open. An untouched empty buffer is reused.
+    fn edit(&mut self, path: PathBuf) -> io::Result<()> {
+        if self.buffer.filename.as_ref() == Some(&path) {
+            return Ok(());
+        }
+        if let Some(index) =
+            self.buffers.iter().position(|buffer| buffer.filename.as_ref() == Some(&path))
+        {
+            self.push_jump();
+            self.switch_buffer(index);
+            return Ok(());
+        }
+        let pristine = self.buffer.filename.is_none()
+            && !self.buffer.modified
+            && self.buffer.content.len() == 1
+            && self.buffer.content[0].is_empty();
+        if pristine {
+            self.open(path)?;
+            self.restore_position();
+            return Ok(());
+        }
+        let previous = self.current;
+        self.push_jump();
+        self.buffers.push(Buffer::default());
+        self.switch_buffer(self.buffers.len() - 1);
+        if let Err(e) = self.open(path) {
+            self.switch_buffer(previous);
+            self.buffers.pop();
+            return Err(e);
+        }
+        self.restore_position();
         Ok(())
     }
 
-    fn refresh_screen(&mut self) -> crossterm::Result<()> {
-        let mut stdout = stdout();
-        queue!(
-            stdout,
-            terminal::Clear(ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
+    /// Every open buffer, the active one included, in `buffers` order.
+    fn all_buffers(&self) -> impl Iterator<Item = &Buffer> {
+        self.buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| if index == self.current { &self.buffer } else { buffer })
+    }
 
-        // Calculate maximum line number width
-        let line_num_width = (self.content.len() + 1).to_string().len();
-        
-        // Display content with line numbers
-        for (i, line) in self.content.iter().enumerate() {
-            let line_num = i + 1;
-            queue!(
-                stdout,
-                style::SetColors(Colors::new(Color::DarkGrey, Color::Black)),
-                cursor::MoveTo(0, i as u16),
-                style::Print(format!("{:>width$} │ ", line_num, width = line_num_width)),
-                style::SetColors(Colors::new(Color::Reset, Color::Reset)),
-                style::Print(line),
-                style::Print("\r\n")
-            )?;
+    /// Puts the cursor back where it was when the file was last left,
+    /// clamped to what the file holds now, and moves the file to the front
+    /// of the recent ones.
+    fn restore_position(&mut self) {
+        let Some(path) = self.buffer.filename.clone() else {
+            return;
+        };
+        if let Some((y, x)) = positions::load(&path) {
+            self.buffer.cursor_y = std::cmp::min(y, self.buffer.content.len() - 1);
+            self.buffer.cursor_x = std::cmp::min(x, self.line_len(self.buffer.cursor_y));
         }
+        let _ = positions::save(&[(&path, (self.buffer.cursor_y, self.buffer.cursor_x))]);
+    }
 
-        // Status bar (bottom line)
-        let status_bar_y = self.terminal_size.1 - 2;
-        let file_name = self.filename
-            .as_ref()
-            .and_then(|p| p.file_name())
-            .and_then(|n| n.to_str())
-            .unwrap_or("[No Name]");
-        
-        let status = format!(
-            " {} - Line {}/{}, Col {} ", 
-            file_name,
-            self.cursor_y + 1,
-            self.content.len(),
-            self.cursor_x + 1
-        );
+    /// Records the cursor position of every open file for `restore_position`.
+    fn remember_positions(&self) {
+        let files: Vec<_> = self
+            .all_buffers()
+            .filter_map(|buffer| Some((buffer.filename.as_deref()?, (buffer.cursor_y, buffer.cursor_x))))
+            .collect();
+        // Failing to remember is no reason to fail anything else
+        let _ = positions::save(&files);
+    }
 
-        let mode_str = format!(" {:?} MODE ", self.mode);
-        let padding = " ".repeat(
-            self.terminal_size.0 as usize 
-            - status.len() 
-            - mode_str.len()
-        );
+    /// `:mksession`: saves the named buffers, their cursors and the windows
+    /// showing them to `path`, or `session::DEFAULT_FILE`.
+    fn make_session(&mut self, path: Option<&str>, force: bool) {
+        let path = Path::new(path.unwrap_or(session::DEFAULT_FILE));
+        if path.exists() && !force {
+            self.status_message = Some(format!(
+                "{} exists (add ! to override)",
+                path.display()
+            ));
+            return;
+        }
+        let mut session = Session { vertical: self.split_vertical, ..Session::default() };
+        // Unnamed buffers can't be reopened, so they and their windows are left out
+        let mut file_of_buffer = Vec::new();
+        for buffer in self.all_buffers() {
+            file_of_buffer.push(buffer.filename.as_deref().map(|name| {
+                let name = fs::canonicalize(name).unwrap_or_else(|_| name.to_path_buf());
+                session.files.push((name, (buffer.cursor_y, buffer.cursor_x)));
+                session.files.len() - 1
+            }));
+        }
+        for (index, window) in self.windows.iter().enumerate() {
+            let window = if index == self.current_window { self.current_view() } else { *window };
+            let Some(file) = file_of_buffer[window.buffer] else {
+                continue;
+            };
+            if index == self.current_window {
+                session.current_window = session.windows.len();
+            }
+            session.windows.push((file, (window.cursor_y, window.cursor_x)));
+        }
+        self.status_message = Some(match session::write(path, &session) {
+            Ok(()) => format!("Session saved to {}", path.display()),
+            Err(e) => format!("Error saving session: {}", e),
+        });
+    }
 
-        queue!(
-            stdout,
-            cursor::MoveTo(0, status_bar_y),
-            style::SetColors(Colors::new(Color::Black, Color::White)),
-            style::Print(&status),
-            style::Print(padding),
-            style::Print(&mode_str),
-            style::SetColors(Colors::new(Color::Reset, Color::Reset)),
-        )?;
+    /// Restores the session saved in `path`: opens its files, skipping ones
+    /// that no longer exist, and lays out its windows.
+    fn load_session(&mut self, path: &Path) {
+        let session = match session::read(path) {
+            Ok(session) => session,
+            Err(e) => {
+                self.status_message = Some(format!("Error loading session: {}", e));
+                return;
+            }
+        };
+        let mut skipped = Vec::new();
+        let mut buffer_of_file = Vec::new();
+        for (file, (y, x)) in &session.files {
+            if !file.is_file() || self.edit(file.clone()).is_err() {
+                skipped.push(file.display().to_string());
+                buffer_of_file.push(None);
+                continue;
+            }
+            self.buffer.cursor_y = std::cmp::min(*y, self.buffer.content.len() - 1);
+            self.buffer.cursor_x = std::cmp::min(*x, self.line_len(self.buffer.cursor_y));
+            buffer_of_file.push(Some(self.current));
+        }
+        let mut windows = Vec::new();
+        let mut current_window = 0;
+        for (index, &(file, (cursor_y, cursor_x))) in session.windows.iter().enumerate() {
+            let Some(buffer) = buffer_of_file[file] else {
+                continue;
+            };
+            if index == session.current_window {
+                current_window = windows.len();
+            }
+            windows.push(Window { buffer, cursor_x, cursor_y, ..Window::default() });
+        }
+        if !windows.is_empty() {
+            let window = windows[current_window];
+            self.windows = windows;
+            self.split_vertical = session.vertical;
+            self.current_window = current_window;
+            self.switch_buffer(window.buffer);
+            self.buffer.cursor_y = std::cmp::min(window.cursor_y, self.buffer.content.len() - 1);
+            self.buffer.cursor_x = std::cmp::min(window.cursor_x, self.line_len(self.buffer.cursor_y));
+        }
+        self.status_message = Some(if skipped.is_empty() {
+            format!("Session loaded from {}", path.display())
+        } else {
+            format!("Session loaded, skipped missing files: {}", skipped.join(", "))
+        });
+    }
 
-        // Help line
-        queue!(
-            stdout,
-            cursor::MoveTo(0, status_bar_y + 1),
-            style::SetColors(Colors::new(Color::DarkGrey, Color::Reset)),
-            style::Print(" CTRL-Q: Quit | i: Insert Mode | ESC: Normal Mode"),
-            style::SetColors(Colors::new(Color::Reset, Color::Reset))
-        )?;
+    /// Makes buffer `index` the active one, parking the current buffer back
+    /// in its slot.
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.current {
+            return;
+        }
+        std::mem::swap(&mut self.buffer, &mut self.buffers[self.current]);
+        std::mem::swap(&mut self.buffer, &mut self.buffers[index]);
+        self.current = index;
+    }
 
-        // Move cursor to current position (accounting for line number margin)
-        queue!(
-            stdout,
-            cursor::MoveTo(
-                (line_num_width + 3 + self.cursor_x) as u16,
-                self.cursor_y as u16
-            )
-        )?;
+    /// The active window's buffer, cursor and scroll position.
+    fn current_view(&self) -> Window {
+        Window {
+            buffer: self.current,
+            cursor_x: self.buffer.cursor_x,
+            cursor_y: self.buffer.cursor_y,
+            row_offset: self.buffer.row_offset,
+            col_offset: self.buffer.col_offset,
+        }
+    }
 
-        stdout.flush()?;
-        Ok(())
+    /// Makes window `index` the active one, showing its buffer with its own
+    /// cursor and scroll position.
+    fn switch_window(&mut self, index: usize) {
+        if index == self.current_window {
+            return;
+        }
+        self.windows[self.current_window] = self.current_view();
+        let window = self.windows[index];
+        self.switch_buffer(window.buffer);
+        // The buffer may have shrunk while edited in another window
+        self.buffer.cursor_y = std::cmp::min(window.cursor_y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(window.cursor_x, self.line_len(self.buffer.cursor_y));
+        self.buffer.row_offset = std::cmp::min(window.row_offset, self.buffer.cursor_y);
+        self.buffer.col_offset = window.col_offset;
+        self.current_window = index;
     }
 
-    fn handle_keypress(&mut self, event: KeyEvent) -> crossterm::Result<()> {
-        match self.mode {
-            Mode::Normal => self.handle_normal_mode(event),
-            Mode::Insert => self.handle_insert_mode(event),
+    /// Splits the active window in two, side by side when `vertical`, and
+    /// moves into the new one, which opens `path` if given.
+    fn split_window(&mut self, vertical: bool, path: Option<&str>) {
+        if self.windows.len() > 1 && self.split_vertical != vertical {
+            self.status_message =
+                Some("Cannot mix horizontal and vertical splits".to_string());
+            return;
+        }
+        let rows = if vertical { self.text_rows() } else { self.text_rows() / 2 };
+        if rows < 2 || self.text_cols() < 4 {
+            self.status_message = Some("Not enough room".to_string());
+            return;
+        }
+        self.split_vertical = vertical;
+        // The new window takes the active slot, the old one follows it
+        self.windows.insert(self.current_window, self.current_view());
+        self.current_window += 1;
+        self.switch_window(self.current_window - 1);
+        if let Some(path) = path {
+            if let Err(e) = self.edit(PathBuf::from(path)) {
+                self.status_message = Some(format!("Error opening file: {}", e));
+            }
         }
     }
 
-    fn handle_normal_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
-        match event.code {
-            KeyCode::Char('i') => self.mode = Mode::Insert,
-            KeyCode::Char('h') => self.move_cursor_left(),
-            KeyCode::Char('j') => self.move_cursor_down(),
-            KeyCode::Char('k') => self.move_cursor_up(),
-            KeyCode::Char('l') => self.move_cursor_right(),
-            KeyCode::Char('q') if event.modifiers.contains(KeyModifiers::CONTROL) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Exit requested",
-                )
-                .into())
+    /// Closes the active window, unless it is the last one.
+    fn close_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.status_message = Some("Cannot close last window".to_string());
+            return;
+        }
+        let index = self.current_window;
+        let next = if index + 1 < self.windows.len() { index + 1 } else { index - 1 };
+        self.switch_window(next);
+        self.windows.remove(index);
+        if next > index {
+            self.current_window -= 1;
+        }
+    }
+
+    /// Closes every window but the active one.
+    fn only_window(&mut self) {
+        let window = self.windows[self.current_window];
+        self.windows = vec![window];
+        self.current_window = 0;
+    }
+
+    /// Handles the key after Ctrl-W: moving between, splitting and closing
+    /// windows.
+    fn window_command(&mut self, code: KeyCode) {
+        let n = self.windows.len();
+        let (prev, next) = if self.split_vertical { ('h', 'l') } else { ('k', 'j') };
+        match code {
+            KeyCode::Char('w') => self.switch_window((self.current_window + 1) % n),
+            KeyCode::Char('W') => self.switch_window((self.current_window + n - 1) % n),
+            KeyCode::Char(c) if c == next => {
+                self.switch_window(std::cmp::min(self.current_window + 1, n - 1))
             }
+            KeyCode::Char(c) if c == prev => {
+                self.switch_window(self.current_window.saturating_sub(1))
+            }
+            KeyCode::Char('s') => self.split_window(false, None),
+            KeyCode::Char('v') => self.split_window(true, None),
+            KeyCode::Char('c') | KeyCode::Char('q') => self.close_window(),
+            KeyCode::Char('o') => self.only_window(),
             _ => {}
         }
-        Ok(())
     }
 
-    fn handle_insert_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
-        match event.code {
-            KeyCode::Esc => self.mode = Mode::Normal,
-            KeyCode::Char(c) => self.insert_char(c),
-            KeyCode::Enter => self.insert_newline(),
-            KeyCode::Backspace => self.handle_backspace(),
-            _ => {}
+    /// Switches to the next buffer, or the previous one, wrapping around.
+    fn cycle_buffer(&mut self, forward: bool) {
+        let len = self.buffers.len();
+        let index = if forward {
+            (self.current + 1) % len
+        } else {
+            (self.current + len - 1) % len
+        };
+        self.push_jump();
+        self.switch_buffer(index);
+        self.status_message = Some(format!(
+            "\"{}\" {} lines",
+            self.display_name(),
+            self.buffer.content.len()
+        ));
+    }
+
+    fn save_file(&mut self) -> io::Result<()> {
+        let path = self
+            .buffer
+            .filename
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No file name"))?;
+        self.write_file(&path, true)
+    }
+
+    /// Writes the buffer's text to `path`. With `own`, `path` is the
+    /// buffer's file, so its disk state, undo history and swap file follow;
+    /// otherwise it is only a copy.
+    fn write_file(&mut self, path: &Path, own: bool) -> io::Result<()> {
+        // Writing the start of a file over the whole of it would lose the
+        // rest, but a copy elsewhere is fine
+        if self.buffer.partial && own {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Only part of the file is loaded; write it elsewhere with :w {file}",
+            ));
+        }
+        if self.trim_trailing_whitespace {
+            let mut trimmed = false;
+            for y in 0..self.buffer.content.len() {
+                let len = self.buffer.content[y].trim_end_matches([' ', '\t']).len();
+                // Clean lines are left alone, so undo snapshots keep sharing them
+                if len < self.buffer.content[y].len() {
+                    // One undo step brings all the whitespace back
+                    if !trimmed {
+                        self.checkpoint();
+                        trimmed = true;
+                    }
+                    self.buffer.content[y].truncate(len);
+                }
+            }
+            let len = self.line_len(self.buffer.cursor_y);
+            let last = match self.mode {
+                Mode::Insert | Mode::Replace => len,
+                _ => len.saturating_sub(1),
+            };
+            self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, last);
         }
+        let ending = self.buffer.line_ending.as_str();
+        let mut text = self.buffer.content.join(ending);
+        // Lines are read without the last one's ending, so it always goes
+        // back on, except in a buffer with no text at all
+        let empty = self.buffer.content.len() == 1 && self.buffer.content[0].is_empty();
+        if self.insert_final_newline && !empty {
+            text.push_str(ending);
+        }
+        fs::write(path, &text)?;
+        if !own {
+            return Ok(());
+        }
+        self.buffer.disk_stamp = disk_stamp(path);
+        let _ = positions::save(&[(path, (self.buffer.cursor_y, self.buffer.cursor_x))]);
+        if self.undofile {
+            // Losing the history is no reason to fail the save itself
+            let _ = undofile::save(path, &text, &self.buffer.undo_stack);
+        }
+        self.remove_swap();
         Ok(())
     }
 
-    fn move_cursor_left(&mut self) {
-        if self.cursor_x > 0 {
-            self.cursor_x -= 1;
+    fn save(&mut self) -> crossterm::Result<()> {
+        let name = match self.buffer.filename {
+            Some(_) => None,
+            None => match self.prompt(":w ")? {
+                Some(name) if !name.is_empty() => Some(PathBuf::from(name)),
+                _ => {
+                    self.status_message = Some("Save aborted".to_string());
+                    return Ok(());
+                }
+            },
+        };
+        self.write_buffer(false, name.as_deref());
+        Ok(())
+    }
+
+    /// Writes the buffer, to `path` if given, and reports the outcome on
+    /// the status line. Returns whether the write succeeded. A buffer with
+    /// no name takes `path` as its file once written there; for any other,
+    /// another path gets a copy, as in vim. Unless `force`, asks first if
+    /// the file changed on disk since it was read, and won't overwrite an
+    /// existing file with a copy.
+    fn write_buffer(&mut self, force: bool, path: Option<&Path>) -> bool {
+        let (target, own) = match (path, &self.buffer.filename) {
+            (Some(path), Some(file)) => (path.to_path_buf(), path == file),
+            (Some(path), None) => (path.to_path_buf(), true),
+            (None, Some(file)) => (file.clone(), true),
+            (None, None) => {
+                self.status_message = Some("No file name".to_string());
+                return false;
+            }
+        };
+        if !own && !force && target.exists() {
+            self.status_message = Some("File exists (add ! to override)".to_string());
+            return false;
+        }
+        if own && !force && self.changed_on_disk() {
+            let answer = self
+                .prompt("File has been modified since read — overwrite? (y/n) ")
+                .ok()
+                .flatten();
+            if !matches!(answer.as_deref(), Some("y" | "yes")) {
+                self.status_message = Some("Not written".to_string());
+                return false;
+            }
+        }
+        if let Err(e) = self.write_file(&target, own) {
+            self.status_message = Some(format!("Error writing file: {}", e));
+            return false;
         }
+        if own {
+            if self.buffer.filename.is_none() {
+                if self.buffer.filetype.is_empty() {
+                    self.set_filetype(filetype::detect(&target));
+                }
+                self.buffer.filename = Some(target.clone());
+            }
+            self.mark_saved();
+            self.status_message = Some(format!("\"{}\" written", self.display_name()));
+        } else {
+            self.status_message = Some(format!("\"{}\" written", target.display()));
+        }
+        true
     }
 
-    fn move_cursor_right(&mut self) {
-        if self.cursor_x < self.content[self.cursor_y].len() {
-            self.cursor_x += 1;
+    /// Whether something else changed the file since we last read or wrote it.
+    fn changed_on_disk(&self) -> bool {
+        match (&self.buffer.filename, self.buffer.disk_stamp) {
+            (Some(path), Some(stamp)) => disk_stamp(path).is_some_and(|now| now != stamp),
+            _ => false,
         }
     }
 
-    fn move_cursor_up(&mut self) {
-        if self.cursor_y > 0 {
-            self.cursor_y -= 1;
-            self.cursor_x = std::cmp::min(self.cursor_x, self.content[self.cursor_y].len());
+    /// Asks `question` on the status line until one of `answers` is typed,
+    /// and returns it. Esc gives the last answer.
+    fn ask(&mut self, question: &str, answers: &[char]) -> crossterm::Result<char> {
+        self.status_message = Some(question.to_string());
+        loop {
+            self.refresh_screen()?;
+            if let Event::Key(event) = event::read()? {
+                match event.code {
+                    KeyCode::Char(c) if answers.contains(&c) => break Ok(c),
+                    KeyCode::Esc => break Ok(answers[answers.len() - 1]),
+                    _ => {}
+                }
+            }
         }
     }
 
-    fn move_cursor_down(&mut self) {
-        if self.cursor_y < self.content.len() - 1 {
-            self.cursor_y += 1;
-            self.cursor_x = std::cmp::min(self.cursor_x, self.content[self.cursor_y].len());
+    /// Lets the user choose one of `items` by fuzzy matching what they type,
+    /// moving through the matches with the arrow keys or Ctrl-N and Ctrl-P.
+    /// Returns `None` if they cancel with Esc.
+    fn pick(
+        &mut self,
+        title: &str,
+        items: Vec<String>,
+        query: &str,
+    ) -> crossterm::Result<Option<String>> {
+        self.picker = Some(Picker {
+            title: title.to_string(),
+            query: query.to_string(),
+            matches: finder::filter(query, &items),
+            items,
+            selected: 0,
+        });
+        let choice = loop {
+            self.refresh_screen()?;
+            let Event::Key(event) = event::read()? else { continue };
+            let picker = self.picker.as_mut().unwrap();
+            let control = event.modifiers.contains(KeyModifiers::CONTROL);
+            let up = event.code == KeyCode::Up
+                || control && matches!(event.code, KeyCode::Char('p' | 'k'));
+            let down = event.code == KeyCode::Down
+                || control && matches!(event.code, KeyCode::Char('n' | 'j'));
+            let last = picker.matches.len().saturating_sub(1);
+            match event.code {
+                KeyCode::Esc => break None,
+                KeyCode::Enter => {
+                    break picker.matches.get(picker.selected).map(|&i| picker.items[i].clone())
+                }
+                _ if up => picker.selected = picker.selected.saturating_sub(1),
+                _ if down => picker.selected = std::cmp::min(picker.selected + 1, last),
+                KeyCode::Backspace => {
+                    picker.query.pop();
+                    picker.matches = finder::filter(&picker.query, &picker.items);
+                    picker.selected = 0;
+                }
+                KeyCode::Char(c) if !control => {
+                    picker.query.push(c);
+                    picker.matches = finder::filter(&picker.query, &picker.items);
+                    picker.selected = 0;
+                }
+                _ => {}
+            }
+        };
+        self.picker = None;
+        Ok(choice)
+    }
+
+    /// Draws the open picker over the windows: the query on top, then as
+    /// many matches as fit with the chosen one highlighted.
+    fn draw_picker(&self, stdout: &mut Stdout, picker: &Picker) -> crossterm::Result<()> {
+        let (width, height) = (self.terminal_size.0 as usize, self.terminal_size.1 as usize);
+        let matches = &picker.matches;
+        let rows = height.saturating_sub(2);
+        // Scroll the list so the chosen entry stays in view
+        let first = (picker.selected + 1).saturating_sub(rows);
+        let prompt = format!(
+            "{} ({}/{})> {}",
+            picker.title,
+            matches.len(),
+            picker.items.len(),
+            picker.query
+        );
+        queue!(
+            stdout,
+            cursor::MoveTo(0, 0),
+            terminal::Clear(ClearType::CurrentLine),
+            style::Print(prompt.chars().take(width).collect::<String>()),
+        )?;
+        for row in 0..rows {
+            queue!(stdout, cursor::MoveTo(0, row as u16 + 1), terminal::Clear(ClearType::CurrentLine))?;
+            let Some(&i) = matches.get(first + row) else { continue };
+            let item = &picker.items[i];
+            let text: String = format!("  {}", item).chars().take(width).collect();
+            if first + row == picker.selected {
+                queue!(
+                    stdout,
+                    style::SetColors(Colors::new(self.theme.status_bar_fg, self.theme.status_bar_bg)),
+                    style::Print(format!("{:<width$}", text)),
+                    style::SetColors(Colors::new(Color::Reset, Color::Reset)),
+                )?;
+            } else {
+                queue!(stdout, style::Print(text))?;
+            }
         }
+        let column = std::cmp::min(prompt.chars().count(), width.saturating_sub(1));
+        queue!(stdout, cursor::MoveTo(column as u16, 0))
     }
 
-    fn insert_char(&mut self, c: char) {
-        let x = self.cursor_x;
-        self.content[self.cursor_y].insert(x, c);
-        self.cursor_x += 1;
+    /// `:find` and Ctrl-P: picks a file below the working directory to edit.
+    fn find_file(&mut self, query: &str) -> crossterm::Result<()> {
+        let files = finder::files(Path::new("."));
+        if files.is_empty() {
+            self.status_message = Some("No files found".to_string());
+            return Ok(());
+        }
+        if let Some(path) = self.pick("Find", files, query)? {
+            if let Err(e) = self.edit(PathBuf::from(path)) {
+                self.status_message = Some(format!("Error opening file: {}", e));
+            }
+        }
+        Ok(())
     }
 
-    fn insert_newline(&mut self) {
-        let y = self.cursor_y;
-        let x = self.cursor_x;
-        let current_line = self.content[y][x..].to_string();
-        self.content[y].truncate(x);
-        self.content.insert(y + 1, current_line);
-        self.cursor_y += 1;
-        self.cursor_x = 0;
+    /// `:oldfiles`: picks one of the recently used files that still exist.
+    fn old_files(&mut self) -> crossterm::Result<()> {
+        let files: Vec<String> = positions::recent()
+            .into_iter()
+            .filter(|path| path.is_file())
+            .map(|path| path.display().to_string())
+            .collect();
+        if files.is_empty() {
+            self.status_message = Some("No recent files".to_string());
+            return Ok(());
+        }
+        if let Some(path) = self.pick("Recent", files, "")? {
+            if let Err(e) = self.edit(PathBuf::from(path)) {
+                self.status_message = Some(format!("Error opening file: {}", e));
+            }
+        }
+        Ok(())
     }
 
-    fn handle_backspace(&mut self) {
-        if self.cursor_x > 0 {
-            let x = self.cursor_x;
-            self.content[self.cursor_y].remove(x - 1);
-            self.cursor_x -= 1;
-        } else if self.cursor_y > 0 {
-            let y = self.cursor_y;
-            let current_line = self.content.remove(y);
-            self.cursor_y -= 1;
-            let previous_len = self.content[self.cursor_y].len();
-            self.content[self.cursor_y].push_str(&current_line);
-            self.cursor_x = previous_len;
+    /// Reads a line of input on the status line. Returns `None` if the user
+    /// cancels with Esc.
+    fn prompt(&mut self, prefix: &str) -> crossterm::Result<Option<String>> {
+        let mut input = String::new();
+        loop {
+            self.status_message = Some(format!("{}{}", prefix, input));
+            self.refresh_screen()?;
+
+            if let Event::Key(event) = event::read()? {
+                match event.code {
+                    KeyCode::Enter => break,
+                    KeyCode::Esc => {
+                        self.status_message = None;
+                        return Ok(None);
+                    }
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+            }
         }
+        self.status_message = None;
+        Ok(Some(input))
     }
-}
 
-fn main() -> crossterm::Result<()> {
-    let mut editor = Editor::new();
-    editor.run()
-}
\ No newline at end of file
+    /// Switches the buffer to `filetype`, taking on its indentation where
+    /// `:set` hasn't chosen one for the buffer.
+    fn set_filetype(&mut self, filetype: &str) {
+        self.buffer.filetype = filetype.to_string();
+        let indent = filetype::indent(filetype);
+        if !self.buffer.tab_width_set {
+            self.buffer.tab_width = indent.map(|(width, _)| width);
+        }
+        if !self.buffer.expandtab_set {
+            self.buffer.expandtab = indent.map(|(_, spaces)| spaces);
+        }
+    }
+
+    /// The tab width in effect for the active buffer.
+    fn tab_width(&self) -> usize {
+        self.buffer.tab_width.unwrap_or(self.tab_width)
+    }
+
+    /// Whether the active buffer indents with spaces.
+    fn expandtab(&self) -> bool {
+        self.buffer.expandtab.unwrap_or(self.expandtab)
+    }
+
+    fn display_name(&self) -> &str {
+        self.buffer.filename
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("[No Name]")
+    }
+
+    fn run(&mut self) -> crossterm::Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), terminal::EnterAlternateScreen, EnableMouseCapture)?;
+
+        // How long input has paused; each idle task runs once per pause
+        let mut idle = Duration::ZERO;
+        loop {
+            self.terminal_size = terminal::size()?;
+            self.refresh_screen()?;
+
+            let autosave = (self.autosave > 0).then(|| Duration::from_secs(self.autosave as u64));
+            // A sequence whose popup is up waits for its next key
+            let waiting = !self.pending_map.is_empty() && !self.which_key;
+            let mapping = waiting.then_some(MAPPING_TIMEOUT);
+            let which_key = self.which_key_entries().is_some().then_some(WHICH_KEY_DELAY);
+            let next = [Some(SWAP_DELAY), autosave, mapping, which_key]
+                .into_iter()
+                .flatten()
+                .filter(|&delay| delay > idle)
+                .min();
+            if let Some(next) = next {
+                if !event::poll(next - idle)? {
+                    idle = next;
+                    if Some(idle) == which_key {
+                        self.which_key = true;
+                    }
+                    if Some(idle) == mapping && !self.which_key && self.feed_pending(true).is_err() {
+                        break;
+                    }
+                    if Some(idle) == autosave {
+                        self.autosave_buffer();
+                    }
+                    if idle == SWAP_DELAY {
+                        self.write_swaps();
+                    }
+                    continue;
+                }
+            }
+            idle = Duration::ZERO;
+            let result = match event::read()? {
+                Event::Key(event) => {
+                    self.which_key = false;
+                    self.pending_map.push(event);
+                    self.feed_pending(false)
+                }
+                Event::Mouse(event) => {
+                    self.handle_mouse(event);
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.remember_positions();
+        // Leaving on an error keeps the swap files, the only copy of
+        // unsaved changes
+        if self.exiting {
+            self.remove_swaps();
+        }
+        execute!(stdout(), DisableMouseCapture, terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        Ok(())
+    }
+
+    /// Writes the file after `autosave` idle seconds, if it has a name and
+    /// changes. Waits while in another mode than normal, so a half-typed
+    /// line isn't trimmed, and leaves alone a file changed on disk.
+    fn autosave_buffer(&mut self) {
+        let eligible = matches!(self.mode, Mode::Normal)
+            && self.buffer.modified
+            && self.buffer.filename.is_some()
+            && !self.buffer.partial
+            && !self.changed_on_disk();
+        if !eligible {
+            return;
+        }
+        match self.save_file() {
+            Ok(()) => {
+                self.mark_saved();
+                self.status_message = Some(format!("\"{}\" autosaved", self.display_name()));
+            }
+            Err(e) => self.status_message = Some(format!("Error writing file: {}", e)),
+        }
+    }
+
+    /// Records every buffer with unsaved changes in its swap file, picking
+    /// an unused name the first time. Warns when that is not the usual
+    /// name, as the file may be open in another editor.
+    fn write_swaps(&mut self) {
+        let current = self.current;
+        let others = self.buffers.iter_mut().enumerate().filter(move |&(index, _)| index != current);
+        let buffers = std::iter::once(&mut self.buffer).chain(others.map(|(_, buffer)| buffer));
+        for buffer in buffers.filter(|buffer| buffer.modified && !buffer.partial) {
+            let Some(path) = &buffer.filename else {
+                continue;
+            };
+            let chosen = buffer.swap_file.is_none();
+            let written = match &buffer.swap_file {
+                Some(swap) => Ok(swap.clone()),
+                None => swapfile::unused(path),
+            }
+            .and_then(|swap| swapfile::write(&swap, &buffer.content.to_vec()).map(|()| swap));
+            match written {
+                Ok(swap) => {
+                    if chosen && swapfile::swap_path(path).is_ok_and(|usual| usual != swap) {
+                        self.status_message = Some(format!(
+                            "Another swap file exists for \"{}\"; is it open elsewhere? Writing {}",
+                            path.display(),
+                            swap.display()
+                        ));
+                    }
+                    buffer.swap_file = Some(swap);
+                }
+                Err(e) => self.status_message = Some(format!("Error writing swap file: {}", e)),
+            }
+        }
+    }
+
+    /// Deletes the active buffer's swap file, once its changes are saved or
+    /// dropped.
+    fn remove_swap(&mut self) {
+        if let Some(swap) = self.buffer.swap_file.take() {
+            let _ = swapfile::remove(&swap);
+        }
+    }
+
+    /// Deletes the swap files of all buffers on a clean exit. Those left by
+    /// an earlier session that were never recovered stay.
+    fn remove_swaps(&self) {
+        for swap in self.all_buffers().filter_map(|buffer| buffer.swap_file.as_ref()) {
+            let _ = swapfile::remove(swap);
+        }
+    }
+
+    /// `:recover` replaces the buffer with the changes in its swap file, as
+    /// one undoable step; `:recover!` deletes the swap file instead.
+    fn recover(&mut self, discard: bool) {
+        let Some(path) = self.buffer.filename.clone() else {
+            self.status_message = Some("No file name".to_string());
+            return;
+        };
+        let usual = swapfile::swap_path(&path).ok();
+        if discard {
+            let removed = usual.as_deref().map_or(Ok(()), swapfile::remove);
+            self.status_message = Some(match removed {
+                Ok(()) => "Swap file deleted".to_string(),
+                Err(e) => format!("Error deleting swap file: {}", e),
+            });
+            if self.buffer.swap_file == usual {
+                self.buffer.swap_file = None;
+            }
+            return;
+        }
+        let Some(content) = swapfile::read(&path) else {
+            self.status_message = Some(format!("No swap file for \"{}\"", path.display()));
+            return;
+        };
+        // The recovered swap file becomes this session's, to be kept up to
+        // date and deleted once the changes are saved
+        if self.buffer.swap_file != usual {
+            self.remove_swap();
+            self.buffer.swap_file = usual;
+        }
+        let content = Rope::from(content);
+        if content == self.buffer.content {
+            self.status_message = Some("Swap file matches the file".to_string());
+            return;
+        }
+        self.push_undo(self.snapshot());
+        self.buffer.content = content;
+        self.buffer.modified = true;
+        self.goto_line(self.buffer.cursor_y);
+        self.status_message = Some("Recovered; write the file to keep the changes".to_string());
+    }
+
+    /// Number of screen rows available for text, above the status and help lines.
+    fn text_rows(&self) -> usize {
+        self.window_rect(self.current_window).height.saturating_sub(1).max(1)
+    }
+
+    /// The screen area of window `index`, including its status bar. Windows
+    /// share the space above the help line evenly, split by a one-column
+    /// border when side by side.
+    fn window_rect(&self, index: usize) -> Rect {
+        let (width, height) = (self.terminal_size.0 as usize, self.terminal_size.1 as usize);
+        let height = height.saturating_sub(1);
+        let n = self.windows.len();
+        if self.split_vertical {
+            let size = width.saturating_sub(n - 1) / n;
+            let x = index * (size + 1);
+            let width = if index == n - 1 { width.saturating_sub(x) } else { size };
+            Rect { x, y: 0, width, height }
+        } else {
+            let size = height / n;
+            let y = index * size;
+            let height = if index == n - 1 { height.saturating_sub(y) } else { size };
+            Rect { x: 0, y, width, height }
+        }
+    }
+
+    /// Width of the line-number margin, including the separator, or 0 when
+    /// line numbers are off.
+    fn gutter_width(&self) -> usize {
+        if self.show_line_numbers || self.relative_numbers {
+            (self.buffer.content.len() + 1).to_string().len() + 3
+        } else {
+            0
+        }
+    }
+
+    /// Number of screen columns available for text, between the margin and
+    /// the scrollbar.
+    fn text_cols(&self) -> usize {
+        self.window_rect(self.current_window)
+            .width
+            .saturating_sub(self.gutter_width() + usize::from(self.scrollbar))
+            .max(1)
+    }
+
+    /// Adjusts `row_offset` and `col_offset` so the cursor is on screen,
+    /// with `scrolloff` lines and `sidescrolloff` columns of room around it
+    /// where the buffer has them.
+    fn scroll(&mut self) {
+        let rows = self.text_rows();
+        let margin = self.scroll_margin();
+        let cursor_y = self.visible_start(self.buffer.cursor_y);
+        let mut above = cursor_y;
+        for _ in 0..margin {
+            if above == 0 {
+                break;
+            }
+            above = self.visible_start(above - 1);
+        }
+        self.buffer.row_offset = self.visible_start(self.buffer.row_offset);
+        if above < self.buffer.row_offset {
+            self.buffer.row_offset = above;
+        } else if self.buffer.folds.is_empty() {
+            let below = std::cmp::min(cursor_y + margin, self.buffer.content.len() - 1);
+            if below >= self.buffer.row_offset + rows {
+                self.buffer.row_offset = below + 1 - rows;
+            }
+        } else {
+            let mut below = cursor_y;
+            for _ in 0..margin {
+                let next = self.next_visible(below);
+                if next >= self.buffer.content.len() {
+                    break;
+                }
+                below = next;
+            }
+            // Closed folds take one row each, so count rows up from the
+            // last line to show
+            let mut top = below;
+            for _ in 1..rows {
+                if top == 0 {
+                    break;
+                }
+                top = self.visible_start(top - 1);
+            }
+            self.buffer.row_offset = std::cmp::max(self.buffer.row_offset, top);
+        }
+
+        let cols = self.text_cols();
+        let side_margin = std::cmp::min(self.sidescrolloff, (cols - 1) / 2);
+        let col = self.display_col(self.buffer.cursor_y, self.buffer.cursor_x);
+        if col < self.buffer.col_offset + side_margin {
+            self.buffer.col_offset = col.saturating_sub(side_margin);
+        } else if col + side_margin >= self.buffer.col_offset + cols {
+            self.buffer.col_offset = col + side_margin + 1 - cols;
+        }
+    }
+
+    /// Lines kept in view above and below the cursor: `scrolloff`, but no
+    /// more than half the window has room for.
+    fn scroll_margin(&self) -> usize {
+        std::cmp::min(self.scrolloff, (self.text_rows() - 1) / 2)
+    }
+
+    fn refresh_screen(&mut self) -> crossterm::Result<()> {
+        let mut stdout = stdout();
+        queue!(
+            stdout,
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0)
+        )?;
+
+        // Compiled once here rather than for every line drawn
+        let search = self.highlight_pattern().and_then(|pattern| self.compile_pattern(pattern, false).ok());
+        // Each window is drawn while switched in, then the active one returns
+        let active = self.current_window;
+        for index in 0..self.windows.len() {
+            self.switch_window(index);
+            self.draw_window(&mut stdout, index == active, search.as_ref())?;
+        }
+        self.switch_window(active);
+        if self.split_vertical {
+            for index in 1..self.windows.len() {
+                let rect = self.window_rect(index);
+                for y in 0..rect.height {
+                    queue!(stdout, cursor::MoveTo(rect.x as u16 - 1, y as u16), style::Print('│'))?;
+                }
+            }
+        }
+        let help_y = self.terminal_size.1.saturating_sub(1);
+
+        // Help line, replaced by the command line or status message when active
+        let help = match (&self.mode, &self.status_message) {
+            (Mode::Command, _) => format!(":{}", self.command_buffer),
+            (Mode::Search, _) => format!("/{}", self.command_buffer),
+            (_, Some(message)) => format!(" {}", message),
+            _ => " CTRL-Q: Quit | CTRL-S: Save | i: Insert Mode | :: Command | ESC: Normal Mode".to_string(),
+        };
+        queue!(
+            stdout,
+            cursor::MoveTo(0, help_y),
+            style::SetColors(Colors::new(Color::DarkGrey, Color::Reset)),
+            style::Print(help),
+            style::SetColors(Colors::new(Color::Reset, Color::Reset))
+        )?;
+
+        if self.which_key {
+            self.draw_which_key(&mut stdout)?;
+        }
+        if let Some(picker) = &self.picker {
+            // The picker owns the cursor, at the end of its query
+            self.draw_picker(&mut stdout, picker)?;
+            stdout.flush()?;
+            return Ok(());
+        }
+
+        // Move cursor to current position (accounting for line number margin)
+        let rect = self.window_rect(self.current_window);
+        if let Mode::Command | Mode::Search = self.mode {
+            queue!(
+                stdout,
+                cursor::MoveTo(1 + self.command_buffer.chars().count() as u16, help_y)
+            )?;
+        } else {
+            queue!(
+                stdout,
+                cursor::MoveTo(
+                    (rect.x + self.gutter_width()
+                        + self.display_col(self.buffer.cursor_y, self.buffer.cursor_x)
+                        - self.buffer.col_offset) as u16,
+                    (rect.y + self.screen_row(self.buffer.cursor_y)) as u16
+                )
+            )?;
+        }
+
+        stdout.flush()?;
+        Ok(())
+    }
+
+    /// Draws the switched-in window: its visible lines and its status bar.
+    /// Only the active window shows the selection and bracket match.
+    fn draw_window(
+        &mut self,
+        stdout: &mut Stdout,
+        active: bool,
+        search: Option<&Regex>,
+    ) -> crossterm::Result<()> {
+        self.scroll();
+        let rect = self.window_rect(self.current_window);
+        let selection = if active { self.selection() } else { None };
+
+        // Calculate maximum line number width
+        let gutter_width = self.gutter_width();
+        let line_num_width = gutter_width.saturating_sub(3);
+
+        // Display the visible slice of content with absolute line numbers
+        let brackets = match self.mode {
+            Mode::Normal | Mode::Insert | Mode::Replace if active => self
+                .matching_bracket(self.buffer.cursor_y, self.buffer.cursor_x)
+                .map(|other| [(self.buffer.cursor_y, self.buffer.cursor_x), other]),
+            _ => None,
+        };
+        let mut i = self.buffer.row_offset;
+        for row in 0..self.text_rows() {
+            if i >= self.buffer.content.len() {
+                break;
+            }
+            queue!(stdout, cursor::MoveTo(rect.x as u16, (rect.y + row) as u16))?;
+            if gutter_width > 0 {
+                // With only relative numbers on, the cursor line shows 0 like vim
+                let relative = i != self.buffer.cursor_y || !self.show_line_numbers;
+                let line_num = if self.relative_numbers && relative {
+                    i.abs_diff(self.buffer.cursor_y)
+                } else {
+                    i + 1
+                };
+                queue!(
+                    stdout,
+                    style::SetColors(Colors::new(
+                        self.theme.line_number_fg,
+                        self.theme.line_number_bg
+                    )),
+                    style::Print(format!("{:>width$} │ ", line_num, width = line_num_width)),
+                )?;
+            }
+            match self.closed_fold(i) {
+                Some(fold) => self.draw_fold(stdout, fold)?,
+                None => self.draw_line(stdout, i, brackets, selection, search)?,
+            }
+            i = self.next_visible(i);
+        }
+        if self.scrollbar {
+            self.draw_scrollbar(stdout, rect)?;
+        }
+
+        // Status bar at the bottom of the window
+        let status_bar_y = (rect.y + self.text_rows()) as u16;
+        let file_name = self.display_name();
+        let buffer_number = if self.buffers.len() > 1 {
+            format!("[{}/{}] ", self.current + 1, self.buffers.len())
+        } else {
+            String::new()
+        };
+
+        let status = format!(
+            " {}{}{} [{}] - Line {}/{}, Col {} ",
+            buffer_number,
+            file_name,
+            if self.buffer.modified { " [+]" } else { "" },
+            self.buffer.line_ending.name(),
+            self.buffer.cursor_y + 1,
+            self.buffer.content.len(),
+            self.buffer.cursor_x + 1
+        );
+
+        let mode_str = match self.recording {
+            _ if !active => String::new(),
+            Some((reg, _)) => format!(" recording @{} | {:?} MODE ", reg, self.mode),
+            None => format!(" {:?} MODE ", self.mode),
+        };
+        let width = rect.width;
+        let status: String = status.chars().take(width.saturating_sub(mode_str.len())).collect();
+        let padding = " ".repeat(
+            width
+                .saturating_sub(status.chars().count())
+                .saturating_sub(mode_str.len()),
+        );
+
+        queue!(
+            stdout,
+            cursor::MoveTo(rect.x as u16, status_bar_y),
+            style::SetColors(Colors::new(self.theme.status_bar_fg, self.theme.status_bar_bg)),
+            style::Print(&status),
+            style::Print(padding),
+            style::Print(&mode_str),
+            style::SetColors(Colors::new(Color::Reset, Color::Reset)),
+        )?;
+        Ok(())
+    }
+
+    /// Draws the scrollbar down the right edge of window `rect`, its thumb
+    /// covering the share of the buffer in view.
+    fn draw_scrollbar(&self, stdout: &mut Stdout, rect: Rect) -> crossterm::Result<()> {
+        let (rows, lines) = (self.text_rows(), self.buffer.content.len());
+        let size = (rows * rows / lines).clamp(1, rows);
+        let top = std::cmp::min(self.buffer.row_offset * rows / lines, rows - size);
+        for row in 0..rows {
+            let color = if (top..top + size).contains(&row) {
+                self.theme.scrollbar_thumb_bg
+            } else {
+                self.theme.scrollbar_bg
+            };
+            queue!(
+                stdout,
+                cursor::MoveTo((rect.x + rect.width - 1) as u16, (rect.y + row) as u16),
+                style::SetColors(Colors::new(Color::Reset, color)),
+                style::Print(' '),
+            )?;
+        }
+        queue!(stdout, style::SetColors(Colors::new(Color::Reset, Color::Reset)))
+    }
+
+    /// Scrolls so the top of the view sits as far through the buffer as
+    /// `row` is down the scrollbar, taking the cursor along if it falls out.
+    fn scroll_to_bar(&mut self, row: usize) {
+        let (rows, lines) = (self.text_rows(), self.buffer.content.len());
+        let top = std::cmp::min(row, rows - 1) * lines / rows;
+        self.buffer.row_offset = self.visible_start(std::cmp::min(top, lines - 1));
+        let margin = self.scroll_margin();
+        let bottom = self.buffer.row_offset + rows - 1 - margin;
+        if self.buffer.cursor_y < self.buffer.row_offset + margin {
+            self.goto_line(self.buffer.row_offset + margin);
+        } else if self.buffer.cursor_y > bottom {
+            self.goto_line(bottom);
+        }
+    }
+
+    fn handle_mouse(&mut self, event: MouseEvent) {
+        if let Mode::Command | Mode::Search = self.mode {
+            return;
+        }
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                let (column, row) = (event.column as usize, event.row as usize);
+                let clicked = (0..self.windows.len()).find(|&index| {
+                    let rect = self.window_rect(index);
+                    (rect.x..rect.x + rect.width).contains(&column)
+                        && (rect.y..rect.y + rect.height).contains(&row)
+                });
+                let Some(index) = clicked else {
+                    return;
+                };
+                self.switch_window(index);
+                let rect = self.window_rect(index);
+                let (column, row) = (column - rect.x, row - rect.y);
+                if row >= self.text_rows() {
+                    return;
+                }
+                if self.scrollbar && column == rect.width - 1 {
+                    self.dragging_scrollbar = true;
+                    self.scroll_to_bar(row);
+                    return;
+                }
+                let mut y = self.buffer.row_offset;
+                for _ in 0..row {
+                    if self.next_visible(y) >= self.buffer.content.len() {
+                        break;
+                    }
+                    y = self.next_visible(y);
+                }
+                // Clicks in the line-number margin land on the first column
+                let col = column.saturating_sub(self.gutter_width()) + self.buffer.col_offset;
+                self.buffer.cursor_y = y;
+                self.buffer.cursor_x = self.col_to_char(y, col);
+                self.snap_cursor();
+                // Clicking elsewhere leaves any snippet being filled in
+                self.snippet_stops.clear();
+            }
+            MouseEventKind::Drag(MouseButton::Left) if self.dragging_scrollbar => {
+                let rect = self.window_rect(self.current_window);
+                self.scroll_to_bar((event.row as usize).saturating_sub(rect.y));
+            }
+            MouseEventKind::Up(MouseButton::Left) => self.dragging_scrollbar = false,
+            MouseEventKind::ScrollDown => {
+                let last = self.buffer.content.len() - 1;
+                self.buffer.row_offset = std::cmp::min(self.buffer.row_offset + WHEEL_SCROLL_LINES, last);
+                // Drag the cursor along only if it would leave the view
+                let top = self.buffer.row_offset + self.scroll_margin();
+                if self.buffer.cursor_y < top {
+                    self.goto_line(top);
+                }
+            }
+            MouseEventKind::ScrollUp => {
+                self.buffer.row_offset = self.buffer.row_offset.saturating_sub(WHEEL_SCROLL_LINES);
+                let bottom = self.buffer.row_offset + self.text_rows() - 1 - self.scroll_margin();
+                if self.buffer.cursor_y > bottom {
+                    self.goto_line(bottom);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_keypress(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        self.status_message = None;
+        let quit_confirmed = std::mem::take(&mut self.quit_pending);
+        if event.code == KeyCode::Char('q') && event.modifiers.contains(KeyModifiers::CONTROL) {
+            return self.quit(quit_confirmed);
+        }
+        if event.code == KeyCode::Char('s') && event.modifiers.contains(KeyModifiers::CONTROL) {
+            return self.save();
+        }
+        if let Mode::Normal = self.mode {
+            if self.change_keys.is_empty() {
+                self.change_start_tick = self.change_tick;
+            }
+        }
+        self.change_keys.push(event);
+        let result = match self.mode {
+            Mode::Normal => self.handle_normal_mode(event),
+            Mode::Insert => self.handle_insert_mode(event),
+            Mode::Replace => self.handle_replace_mode(event),
+            Mode::Command | Mode::Search => self.handle_command_mode(event),
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => self.handle_visual_mode(event),
+        };
+        if let Mode::Normal | Mode::Visual | Mode::VisualLine | Mode::VisualBlock = self.mode {
+            self.snap_cursor();
+        }
+        // Snippet stops only last while the insert that expanded them does
+        if !matches!(self.mode, Mode::Insert) {
+            self.snippet_stops.clear();
+        }
+        // The cursor rests on the first line of a closed fold, and editing
+        // inside one opens it
+        match self.mode {
+            Mode::Insert | Mode::Replace => {
+                while let Some(fold) = self.closed_fold(self.buffer.cursor_y) {
+                    self.set_fold(fold, Some(false));
+                }
+            }
+            _ => self.buffer.cursor_y = self.visible_start(self.buffer.cursor_y),
+        }
+        self.finish_change();
+        result
+    }
+
+    /// Once a normal mode command (including any insert session it started)
+    /// is complete, keeps its keys for `.` if it changed the buffer.
+    fn finish_change(&mut self) {
+        match self.mode {
+            Mode::Command | Mode::Search => self.change_keys.clear(),
+            Mode::Normal
+                if self.pending_keys.is_empty()
+                    && self.count.is_none()
+                    && self.selected_register.is_none() =>
+            {
+                // `.` replays record themselves, leaving nothing here
+                if self.change_tick != self.change_start_tick && !self.change_keys.is_empty() {
+                    self.last_change = std::mem::take(&mut self.change_keys);
+                } else {
+                    self.change_keys.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Replays the last change `count` times, as `.` does in vim.
+    fn repeat_change(&mut self, count: usize) -> crossterm::Result<()> {
+        self.change_keys.clear();
+        let keys = self.last_change.clone();
+        for _ in 0..count {
+            for &key in &keys {
+                self.handle_keypress(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the keys typed so far through the keymap. Keys that may start a
+    /// longer mapping wait for more, unless the wait `timed_out`.
+    fn feed_pending(&mut self, mut timed_out: bool) -> crossterm::Result<()> {
+        while !self.pending_map.is_empty() {
+            let mode = self.map_mode();
+            if !timed_out && self.keymap.is_prefix(mode, &self.pending_map) {
+                return Ok(());
+            }
+            let (len, keys) = match self.keymap.longest_match(mode, &self.pending_map) {
+                Some((len, keys)) => (len, keys.to_vec()),
+                None => (1, vec![self.pending_map[0]]),
+            };
+            self.pending_map.drain(..len);
+            for key in keys {
+                self.record_key(key);
+                self.handle_keypress(key)?;
+            }
+            timed_out = false;
+        }
+        Ok(())
+    }
+
+    /// The title and entries of the popup for the key sequence under way:
+    /// the mappings it begins, or the keys a built-in command such as `d`
+    /// or `z` takes next. Only normal and visual mode have one.
+    fn which_key_entries(&self) -> Option<(String, Vec<(String, String)>)> {
+        if !matches!(self.map_mode(), "normal" | "visual") {
+            return None;
+        }
+        let (title, entries) = if !self.pending_map.is_empty() {
+            let title = self.pending_map.iter().map(|&key| keymap::key_name(key)).collect();
+            (title, self.keymap.continuations(self.map_mode(), &self.pending_map))
+        } else {
+            let entries = pending_hints(&self.pending_keys)
+                .iter()
+                .map(|&(keys, action)| (keys.to_string(), action.to_string()))
+                .collect();
+            (self.pending_keys.clone(), entries)
+        };
+        (!entries.is_empty()).then_some((title, entries))
+    }
+
+    /// Draws the popup of keys that can follow in a box above the help line.
+    fn draw_which_key(&self, stdout: &mut Stdout) -> crossterm::Result<()> {
+        let Some((title, entries)) = self.which_key_entries() else {
+            return Ok(());
+        };
+        let (width, height) = (self.terminal_size.0 as usize, self.terminal_size.1 as usize);
+        let key_width = entries.iter().map(|(keys, _)| keys.chars().count()).max().unwrap_or(0);
+        let lines: Vec<String> = entries
+            .iter()
+            .take(height.saturating_sub(3))
+            .map(|(keys, action)| format!(" {:<key_width$}  {} ", keys, action))
+            .collect();
+        let inner = lines
+            .iter()
+            .map(|line| line.chars().count())
+            .chain([title.chars().count() + 2])
+            .max()
+            .unwrap_or(0)
+            .min(width.saturating_sub(2));
+        let top = height.saturating_sub(lines.len() + 3);
+        let heading: String = format!(" {} ", title).chars().take(inner).collect();
+        let mut rows = vec![format!("┌{}{}┐", heading, "─".repeat(inner - heading.chars().count()))];
+        for line in &lines {
+            let line: String = line.chars().take(inner).collect();
+            rows.push(format!("│{:<inner$}│", line));
+        }
+        rows.push(format!("└{}┘", "─".repeat(inner)));
+        queue!(stdout, style::SetColors(Colors::new(self.theme.status_bar_fg, self.theme.status_bar_bg)))?;
+        for (i, row) in rows.iter().enumerate() {
+            queue!(stdout, cursor::MoveTo(0, (top + i) as u16), style::Print(row))?;
+        }
+        queue!(stdout, style::SetColors(Colors::new(Color::Reset, Color::Reset)))
+    }
+
+    /// The keymap mode the current mode takes its mappings from.
+    fn map_mode(&self) -> &'static str {
+        match self.mode {
+            Mode::Normal => "normal",
+            Mode::Insert | Mode::Replace => "insert",
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => "visual",
+            Mode::Command | Mode::Search => "command",
+        }
+    }
+
+    /// Adds a key typed by the user to the macro being recorded. Replayed
+    /// keys never reach this, so `.` and `@` inside a recording stay as is.
+    fn record_key(&mut self, event: KeyEvent) {
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(event);
+        }
+    }
+
+    /// Stops recording and stores the macro, minus the `q` that ended it.
+    fn stop_recording(&mut self) {
+        if let Some((reg, mut keys)) = self.recording.take() {
+            keys.pop();
+            self.macros.insert(reg, keys);
+        }
+    }
+
+    /// Replays the macro in register `reg` `count` times. A macro that is
+    /// already playing is not started again, so recursive macros end.
+    fn play_macro(&mut self, reg: char, count: usize) -> crossterm::Result<()> {
+        if self.playing.contains(&reg) {
+            self.status_message = Some(format!("Recursive macro @{} stopped", reg));
+            return Ok(());
+        }
+        let keys = match self.macros.get(&reg) {
+            Some(keys) => keys.clone(),
+            None => {
+                self.status_message = Some(format!("Register {} is empty", reg));
+                return Ok(());
+            }
+        };
+        self.last_macro = Some(reg);
+        self.change_keys.clear();
+        self.playing.push(reg);
+        let mut result = Ok(());
+        'replay: for _ in 0..count {
+            for &key in &keys {
+                result = self.handle_keypress(key);
+                if result.is_err() {
+                    break 'replay;
+                }
+            }
+        }
+        self.playing.pop();
+        result
+    }
+
+    /// Whether any open buffer has unsaved changes.
+    fn any_modified(&self) -> bool {
+        self.buffer.modified || self.buffers.iter().any(|buffer| buffer.modified)
+    }
+
+    fn quit(&mut self, confirmed: bool) -> crossterm::Result<()> {
+        if self.any_modified() && !confirmed {
+            self.status_message = Some(
+                "No write since last change (press Ctrl-Q again to override)".to_string(),
+            );
+            self.quit_pending = true;
+            return Ok(());
+        }
+        self.exit()
+    }
+
+    /// Ends the editor loop as a clean exit.
+    fn exit(&mut self) -> crossterm::Result<()> {
+        self.exiting = true;
+        Err(io::Error::other("Exit requested"))
+    }
+
+    fn handle_normal_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        if !self.pending_keys.is_empty() {
+            let prefix = std::mem::take(&mut self.pending_keys);
+            return self.handle_pending(&prefix, event);
+        }
+        if self.push_count_digit(event.code) {
+            return Ok(());
+        }
+        let count = self.count.take();
+        if self.apply_motion(event, count) {
+            self.keep_count_if_pending(count);
+            return Ok(());
+        }
+        match event.code {
+            KeyCode::Char('w') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.pending_keys.push_str("<C-w>");
+            }
+            KeyCode::Char('v') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.start_visual(Mode::VisualBlock)
+            }
+            KeyCode::Char('p') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                return self.find_file("");
+            }
+            KeyCode::Char('r') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                for _ in 0..count.unwrap_or(1) {
+                    self.redo();
+                }
+            }
+            KeyCode::Char('o') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.jump_back(count.unwrap_or(1))
+            }
+            // Terminals send Ctrl-I as Tab
+            KeyCode::Tab => self.jump_forward(count.unwrap_or(1)),
+            KeyCode::Char('i') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.jump_forward(count.unwrap_or(1))
+            }
+            KeyCode::Char(c @ ('a' | 'x')) if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                let step = count.unwrap_or(1) as i64;
+                self.add_to_number(if c == 'a' { step } else { -step });
+            }
+            KeyCode::Esc => self.highlight_search = false,
+            KeyCode::Char('u') => {
+                for _ in 0..count.unwrap_or(1) {
+                    self.undo();
+                }
+            }
+            KeyCode::Char('i') => self.mode = Mode::Insert,
+            KeyCode::Char('a') => {
+                self.move_cursor_right();
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('A') => {
+                self.buffer.cursor_x = self.line_len(self.buffer.cursor_y);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('I') => {
+                self.buffer.cursor_x = self.first_non_blank(self.buffer.cursor_y);
+                self.mode = Mode::Insert;
+            }
+            KeyCode::Char('.') => return self.repeat_change(count.unwrap_or(1)),
+            KeyCode::Char('x') => {
+                if let Some(text) = self.delete_chars(count.unwrap_or(1)) {
+                    self.set_register(Register { text: vec![text], linewise: false });
+                }
+            }
+            KeyCode::Char(':') => self.mode = Mode::Command,
+            KeyCode::Char('/') => self.mode = Mode::Search,
+            KeyCode::Char('v') => self.start_visual(Mode::Visual),
+            KeyCode::Char('V') => self.start_visual(Mode::VisualLine),
+            KeyCode::Char('d') => self.pending_keys.push('d'),
+            KeyCode::Char('c') => self.pending_keys.push('c'),
+            KeyCode::Char('y') => self.pending_keys.push('y'),
+            KeyCode::Char('>') => self.pending_keys.push('>'),
+            KeyCode::Char('<') => self.pending_keys.push('<'),
+            KeyCode::Char('p') => self.paste(true, count.unwrap_or(1)),
+            KeyCode::Char('P') => self.paste(false, count.unwrap_or(1)),
+            KeyCode::Char('J') => self.join_lines(count.unwrap_or(2)),
+            KeyCode::Char('~') => {
+                let (x, y) = (self.buffer.cursor_x, self.buffer.cursor_y);
+                let len = self.line_len(y);
+                if x < len {
+                    let end = std::cmp::min(x.saturating_add(count.unwrap_or(1)), len);
+                    self.checkpoint();
+                    self.toggle_case(y, x, end);
+                    self.buffer.cursor_x = std::cmp::min(end, len - 1);
+                }
+            }
+            KeyCode::Char('q') if self.recording.is_some() => self.stop_recording(),
+            KeyCode::Char('q') => self.pending_keys.push('q'),
+            KeyCode::Char('@') => self.pending_keys.push('@'),
+            KeyCode::Char('m') => self.pending_keys.push('m'),
+            KeyCode::Char('z') => self.pending_keys.push('z'),
+            KeyCode::Char('r') => self.pending_keys.push('r'),
+            KeyCode::Char('R') => {
+                self.replaced.clear();
+                self.mode = Mode::Replace;
+            }
+            KeyCode::Char('o') => self.open_line(self.buffer.cursor_y + 1),
+            KeyCode::Char('O') => self.open_line(self.buffer.cursor_y),
+            _ => {}
+        }
+        self.keep_count_if_pending(count);
+        Ok(())
+    }
+
+    fn handle_visual_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        if !self.pending_keys.is_empty() {
+            let prefix = std::mem::take(&mut self.pending_keys);
+            return self.handle_pending(&prefix, event);
+        }
+        if self.push_count_digit(event.code) {
+            return Ok(());
+        }
+        let count = self.count.take();
+        if self.apply_motion(event, count) {
+            self.keep_count_if_pending(count);
+            return Ok(());
+        }
+        if event.code == KeyCode::Char('v') && event.modifiers.contains(KeyModifiers::CONTROL) {
+            self.mode = match self.mode {
+                Mode::VisualBlock => Mode::Normal,
+                _ => Mode::VisualBlock,
+            };
+            return Ok(());
+        }
+        match (event.code, &self.mode) {
+            (KeyCode::Esc, _)
+            | (KeyCode::Char('v'), Mode::Visual)
+            | (KeyCode::Char('V'), Mode::VisualLine) => self.mode = Mode::Normal,
+            // Switching between v and V keeps the anchor
+            (KeyCode::Char('v'), _) => self.mode = Mode::Visual,
+            (KeyCode::Char('V'), _) => self.mode = Mode::VisualLine,
+            (KeyCode::Char('d') | KeyCode::Char('x'), _) => {
+                if let Some(selection) = self.selection() {
+                    let text = self.delete_selection(selection);
+                    self.set_register(Register { text, linewise: selection.linewise });
+                }
+                self.mode = Mode::Normal;
+            }
+            (KeyCode::Char(c @ ('>' | '<')), _) => {
+                if let Some(selection) = self.selection() {
+                    self.shift_lines(selection.start.0, selection.end.0, c == '>');
+                }
+                self.mode = Mode::Normal;
+            }
+            (KeyCode::Char(c @ ('I' | 'A')), Mode::VisualBlock) => {
+                let selection = self.selection().unwrap();
+                let x = if c == 'I' { selection.start.1 } else { selection.end.1 + 1 };
+                let y = selection.start.0;
+                self.mode = Mode::Insert;
+                // Appending past a short first line pads it out to the block,
+                // undone together with what gets typed
+                let len = self.line_len(y);
+                if x > len {
+                    self.checkpoint();
+                    self.buffer.content[y].push_str(&" ".repeat(x - len));
+                    self.buffer.modified = true;
+                }
+                self.block_insert = Some(BlockInsert {
+                    first: y,
+                    last: selection.end.0,
+                    x,
+                    line_len: self.line_len(y),
+                    append: c == 'A',
+                });
+                self.buffer.cursor_y = y;
+                self.buffer.cursor_x = x;
+            }
+            (KeyCode::Char('~'), _) => {
+                if let Some(selection) = self.selection() {
+                    self.checkpoint();
+                    for y in selection.start.0..=selection.end.0 {
+                        if let Some((start, end)) = selection.columns(y, self.line_len(y)) {
+                            self.toggle_case(y, start, end);
+                        }
+                    }
+                    self.set_cursor(selection.start);
+                }
+                self.mode = Mode::Normal;
+            }
+            (KeyCode::Char('S'), _) => self.pending_keys.push('S'),
+            (KeyCode::Char('z'), _) => self.pending_keys.push('z'),
+            // Ex commands get the selected lines as their range, through
+            // the `'<` and `'>` marks
+            (KeyCode::Char(c @ (':' | '!')), _) => {
+                if let Some(selection) = self.selection() {
+                    self.buffer.marks.insert('<', selection.start);
+                    self.buffer.marks.insert('>', selection.end);
+                }
+                self.command_buffer = format!("'<,'>{}", if c == '!' { "!" } else { "" });
+                self.mode = Mode::Command;
+            }
+            (KeyCode::Char('y'), _) => {
+                if let Some(selection) = self.selection() {
+                    let text = self.selection_text(selection);
+                    self.set_register(Register { text, linewise: selection.linewise });
+                    self.set_cursor(if selection.linewise {
+                        (selection.start.0, self.buffer.cursor_x)
+                    } else {
+                        selection.start
+                    });
+                }
+                self.mode = Mode::Normal;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_visual(&mut self, mode: Mode) {
+        self.visual_anchor = (self.buffer.cursor_y, self.buffer.cursor_x);
+        self.mode = mode;
+    }
+
+    /// Digits build up a count prefix; a leading 0 is the line-start motion.
+    /// Returns whether the key was consumed as part of a count.
+    fn push_count_digit(&mut self, code: KeyCode) -> bool {
+        if let KeyCode::Char(c @ '0'..='9') = code {
+            if c != '0' || self.count.is_some() {
+                let digit = c.to_digit(10).unwrap() as usize;
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Keeps the count around for the second key of a sequence.
+    fn keep_count_if_pending(&mut self, count: Option<usize>) {
+        if !self.pending_keys.is_empty() {
+            self.count = count;
+        }
+    }
+
+    /// Cursor motions shared by normal and visual mode. Returns whether
+    /// `event` was a motion.
+    fn apply_motion(&mut self, event: KeyEvent, count: Option<usize>) -> bool {
+        if event.modifiers.contains(KeyModifiers::CONTROL) {
+            let page: fn(&mut Editor) = match event.code {
+                KeyCode::Char('f') => Editor::page_down,
+                KeyCode::Char('b') => Editor::page_up,
+                KeyCode::Char('d') => |e| e.half_page(true),
+                KeyCode::Char('u') => |e| e.half_page(false),
+                _ => return false,
+            };
+            for _ in 0..count.unwrap_or(1) {
+                page(self);
+            }
+            return true;
+        }
+        let n = count.unwrap_or(1);
+        match event.code {
+            KeyCode::Char(c @ ('h' | 'j' | 'k' | 'l' | 'w' | 'b' | 'e' | 'n' | 'N')) => {
+                for _ in 0..n {
+                    self.repeatable_motion(c);
+                }
+            }
+            KeyCode::Char('0') => self.smart_home(false),
+            KeyCode::Char('^') | KeyCode::Home => self.smart_home(true),
+            KeyCode::Char('$') => {
+                // A count moves down that many lines first, as in vim
+                self.goto_line(self.buffer.cursor_y + n - 1);
+                self.buffer.cursor_x = self.line_len(self.buffer.cursor_y).saturating_sub(1);
+            }
+            KeyCode::Char('g') => self.pending_keys.push('g'),
+            KeyCode::Char('"') => self.pending_keys.push('"'),
+            KeyCode::Char('%') => {
+                self.push_jump();
+                self.jump_to_match();
+            }
+            KeyCode::Char(c @ ('*' | '#')) => self.search_word(c == '*', n),
+            KeyCode::Char(c @ ('f' | 't' | 'F' | 'T')) => self.pending_keys.push(c),
+            KeyCode::Char(c @ (';' | ',')) => {
+                if let Some((kind, target)) = self.last_find {
+                    let kind = if c == ';' { kind } else { reverse_find(kind) };
+                    self.find_in_line(kind, target, n, true);
+                }
+            }
+            KeyCode::Char('`') => self.pending_keys.push('`'),
+            KeyCode::Char('\'') => self.pending_keys.push('\''),
+            KeyCode::Char('G') => {
+                self.push_jump();
+                match count {
+                    Some(n) => self.goto_line(n.saturating_sub(1)),
+                    None => self.goto_line(self.buffer.content.len() - 1),
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Motions that a count repeats.
+    fn repeatable_motion(&mut self, c: char) {
+        match c {
+            'h' => self.move_cursor_left(),
+            'j' => self.move_cursor_down(),
+            'k' => self.move_cursor_up(),
+            'l' => self.move_cursor_right(),
+            'w' => self.set_cursor(self.next_word_start(self.buffer.cursor_y, self.buffer.cursor_x)),
+            'b' => self.set_cursor(self.prev_word_start(self.buffer.cursor_y, self.buffer.cursor_x)),
+            'e' => self.set_cursor(self.word_end(self.buffer.cursor_y, self.buffer.cursor_x)),
+            'n' => self.search_next(true),
+            'N' => self.search_next(false),
+            _ => {}
+        }
+    }
+
+    /// The current visual selection, ordered from start to end.
+    fn selection(&self) -> Option<Selection> {
+        let linewise = match self.mode {
+            Mode::Visual | Mode::VisualBlock => false,
+            Mode::VisualLine => true,
+            _ => return None,
+        };
+        let cursor = (self.buffer.cursor_y, self.buffer.cursor_x);
+        if let Mode::VisualBlock = self.mode {
+            let (anchor_y, anchor_x) = self.visual_anchor;
+            let start = (std::cmp::min(anchor_y, cursor.0), std::cmp::min(anchor_x, cursor.1));
+            let end = (std::cmp::max(anchor_y, cursor.0), std::cmp::max(anchor_x, cursor.1));
+            return Some(Selection { start, end, linewise, block: true });
+        }
+        let (start, end) = if self.visual_anchor <= cursor {
+            (self.visual_anchor, cursor)
+        } else {
+            (cursor, self.visual_anchor)
+        };
+        Some(Selection { start, end, linewise, block: false })
+    }
+
+    /// Continues a multi-key command such as `dd` or `gcc`, either running it
+    /// or waiting for more keys. Unknown sequences are dropped, like vim does.
+    fn handle_pending(&mut self, prefix: &str, event: KeyEvent) -> crossterm::Result<()> {
+        let count = self.count.take();
+        match (prefix, event.code) {
+            ("d", KeyCode::Char('d')) => {
+                let text = self.delete_lines(count.unwrap_or(1));
+                self.set_register(Register { text, linewise: true });
+            }
+            ("d" | "c", KeyCode::Char('w')) => {
+                self.change_word(prefix == "c", false, count.unwrap_or(1))
+            }
+            ("d" | "c", KeyCode::Char('i')) => {
+                self.pending_keys = format!("{}i", prefix);
+                self.count = count;
+            }
+            ("di" | "ci", KeyCode::Char('w')) => self.change_word(prefix == "ci", true, 1),
+            ("d" | "c" | "y", KeyCode::Char('s'))
+            | ("ys", KeyCode::Char('i' | 'w' | 's'))
+            | ("ysi", KeyCode::Char('w')) => {
+                let KeyCode::Char(c) = event.code else { unreachable!() };
+                self.pending_keys = format!("{}{}", prefix, c);
+                self.count = count;
+            }
+            ("ysiw" | "ysw" | "yss", KeyCode::Char(c)) => {
+                let y = self.buffer.cursor_y;
+                let (start, end) = match prefix {
+                    "ysiw" => self.word_range(true, false, 1),
+                    "ysw" => self.word_range(false, true, count.unwrap_or(1)),
+                    _ => (self.first_non_blank(y), self.buffer.content[y].trim_end().chars().count()),
+                };
+                if end > start {
+                    self.add_surround(&[((y, start), (y, end))], c);
+                }
+            }
+            ("S", KeyCode::Char(c)) => {
+                if let Some(selection) = self.selection() {
+                    let ranges: Vec<_> = if selection.block {
+                        (selection.start.0..=selection.end.0)
+                            .filter_map(|y| {
+                                let (start, end) = selection.columns(y, self.line_len(y))?;
+                                (end > start).then_some(((y, start), (y, end)))
+                            })
+                            .collect()
+                    } else {
+                        let (first, last) = (selection.start.0, selection.end.0);
+                        let end = selection.columns(last, self.line_len(last)).map_or(0, |(_, end)| end);
+                        let start = if selection.linewise { 0 } else { selection.start.1 };
+                        vec![((first, start), (last, end))]
+                    };
+                    self.add_surround(&ranges, c);
+                }
+                self.mode = Mode::Normal;
+            }
+            ("ds", KeyCode::Char(c)) => self.change_surround(c, None),
+            ("cs", KeyCode::Char(c)) if surround_pair(c).is_some() => {
+                self.pending_keys = format!("cs{}", c);
+            }
+            ("cs", KeyCode::Char(c)) => {
+                self.status_message = Some(format!("Not a surrounding character: {}", c));
+            }
+            (_, KeyCode::Char(c)) if prefix.starts_with("cs") && prefix.chars().count() == 3 => {
+                if let Some(old) = prefix.chars().nth(2) {
+                    self.change_surround(old, Some(c));
+                }
+            }
+            ("y", KeyCode::Char('y')) => {
+                let end = std::cmp::min(self.buffer.cursor_y + count.unwrap_or(1), self.buffer.content.len());
+                let text = self.buffer.content.range(self.buffer.cursor_y..end).cloned().collect();
+                self.set_register(Register { text, linewise: true });
+            }
+            (">", KeyCode::Char('>')) | ("<", KeyCode::Char('<')) => {
+                let last = self.buffer.cursor_y + count.unwrap_or(1) - 1;
+                self.shift_lines(self.buffer.cursor_y, last, prefix == ">");
+            }
+            ("g", KeyCode::Char('g')) if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.word_count()
+            }
+            ("g", KeyCode::Char('g')) => {
+                self.push_jump();
+                self.goto_line(count.unwrap_or(1).saturating_sub(1));
+            }
+            ("g", KeyCode::Char('c')) => match self.selection() {
+                Some(selection) => {
+                    self.toggle_comments(selection.start.0, selection.end.0);
+                    self.mode = Mode::Normal;
+                }
+                None => {
+                    self.pending_keys.push_str("gc");
+                    self.count = count;
+                }
+            },
+            ("z", KeyCode::Char('f')) => {
+                if let Some(selection) = self.selection() {
+                    let (start, end) = (selection.start.0, selection.end.0);
+                    self.buffer.folds.retain(|fold| fold.start != start || fold.end != end);
+                    self.buffer.folds.push(Fold { start, end, closed: true });
+                    self.buffer.cursor_y = start;
+                    self.mode = Mode::Normal;
+                }
+            }
+            ("z", KeyCode::Char(c @ ('o' | 'c' | 'a' | 'd'))) => self.fold_command(c),
+            ("z", KeyCode::Char(c @ ('R' | 'M'))) => {
+                for fold in &mut self.buffer.folds {
+                    fold.closed = c == 'M';
+                }
+            }
+            ("z", KeyCode::Char('E')) => self.buffer.folds.clear(),
+            ("z", KeyCode::Char(c @ ('t' | 'z' | 'b'))) => {
+                // With a count, vim scrolls to that line instead
+                if let Some(line) = count {
+                    self.goto_line(line - 1);
+                }
+                let rows = self.text_rows();
+                self.scroll_cursor_to(match c {
+                    't' => 0,
+                    'z' => (rows - 1) / 2,
+                    _ => rows - 1,
+                });
+            }
+            ("gc", KeyCode::Char('c')) => {
+                let last = self.buffer.cursor_y + count.unwrap_or(1) - 1;
+                self.toggle_comments(self.buffer.cursor_y, last);
+            }
+            ("q", KeyCode::Char(c)) if c.is_ascii_alphanumeric() => {
+                self.recording = Some((c, Vec::new()));
+            }
+            ("@", KeyCode::Char('@')) => match self.last_macro {
+                Some(reg) => return self.play_macro(reg, count.unwrap_or(1)),
+                None => self.status_message = Some("No previous macro".to_string()),
+            },
+            ("@", KeyCode::Char(c)) => return self.play_macro(c, count.unwrap_or(1)),
+            ("m", KeyCode::Char(c)) if c.is_ascii_alphabetic() => {
+                self.buffer.marks.insert(c, (self.buffer.cursor_y, self.buffer.cursor_x));
+            }
+            ("f" | "t" | "F" | "T", KeyCode::Char(c)) => {
+                let kind = prefix.chars().next().unwrap();
+                self.last_find = Some((kind, c));
+                self.find_in_line(kind, c, count.unwrap_or(1), false);
+            }
+            ("`", KeyCode::Char(c)) => self.jump_to_mark(c, false),
+            ("'", KeyCode::Char(c)) => self.jump_to_mark(c, true),
+            ("<C-w>", code) => self.window_command(code),
+            ("r", KeyCode::Char(c)) => self.replace_chars(Some(c), count.unwrap_or(1)),
+            ("r", KeyCode::Enter) => self.replace_chars(None, count.unwrap_or(1)),
+            ("\"", KeyCode::Char(c)) => {
+                self.selected_register = Some(c);
+                self.count = count;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_command_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        match event.code {
+            KeyCode::Esc => {
+                self.command_buffer.clear();
+                self.mode = Mode::Normal;
+                self.highlight_search = false;
+            }
+            KeyCode::Enter => {
+                let cmd = std::mem::take(&mut self.command_buffer);
+                let mode = std::mem::replace(&mut self.mode, Mode::Normal);
+                if let Mode::Search = mode {
+                    self.search(cmd);
+                    return Ok(());
+                }
+                return self.run_command(cmd.trim());
+            }
+            KeyCode::Backspace => {
+                // Backspacing over the ':' or '/' leaves the command line, as in vim
+                if self.command_buffer.is_empty() {
+                    self.mode = Mode::Normal;
+                } else {
+                    self.command_buffer.pop();
+                }
+            }
+            KeyCode::Char(c) => self.command_buffer.push(c),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Runs a `/` search, reusing the last pattern when the input is empty.
+    fn search(&mut self, pattern: String) {
+        if !pattern.is_empty() {
+            self.last_search = Some(pattern);
+        }
+        self.search_next(true);
+    }
+
+    /// `*` and `#`: searches forward or backward for the `count`th other
+    /// occurrence of the word under the cursor, or of the next word on the
+    /// line, as a whole word.
+    fn search_word(&mut self, forward: bool, count: usize) {
+        let chars: Vec<char> = self.buffer.content[self.buffer.cursor_y].chars().collect();
+        let Some(mut start) = (self.buffer.cursor_x..chars.len()).find(|&x| is_word_char(chars[x])) else {
+            self.status_message = Some("No identifier under cursor".to_string());
+            return;
+        };
+        if start == self.buffer.cursor_x {
+            while start > 0 && is_word_char(chars[start - 1]) {
+                start -= 1;
+            }
+        }
+        let end = (start..chars.len()).find(|&x| !is_word_char(chars[x])).unwrap_or(chars.len());
+        let word: String = chars[start..end].iter().collect();
+        self.last_search = Some(format!("\\v\\<{}\\>", word));
+        // From the word's start, so `#` skips the occurrence under the cursor
+        self.buffer.cursor_x = start;
+        for _ in 0..count {
+            self.search_next(forward);
+        }
+    }
+
+    /// Jumps to the next (`n`) or previous (`N`) match of the last pattern.
+    fn search_next(&mut self, forward: bool) {
+        self.highlight_search = true;
+        let pattern = match &self.last_search {
+            Some(pattern) => pattern.clone(),
+            None => {
+                self.status_message = Some("No previous search pattern".to_string());
+                return;
+            }
+        };
+        let regex = match self.compile_pattern(&pattern, false) {
+            Ok(regex) => regex,
+            Err(message) => {
+                self.status_message = Some(format!("Invalid pattern: {}", message));
+                return;
+            }
+        };
+        let found = if forward {
+            self.find_forward(&regex, self.buffer.cursor_y, self.buffer.cursor_x)
+        } else {
+            self.find_backward(&regex, self.buffer.cursor_y, self.buffer.cursor_x)
+        };
+        match found {
+            Some(pos) => {
+                self.push_jump();
+                self.set_cursor(pos);
+            }
+            None => self.status_message = Some(format!("Pattern not found: {}", pattern)),
+        }
+    }
+
+    /// Builds the matcher for a search or substitute pattern. Patterns are
+    /// plain text unless the `regex` option is set or they start with `\v`.
+    fn compile_pattern(&self, pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+        match pattern.strip_prefix("\\v") {
+            Some(pattern) => Regex::new(pattern, ignore_case),
+            None if self.regex => Regex::new(pattern, ignore_case),
+            None => Ok(Regex::literal(pattern, ignore_case)),
+        }
+    }
+
+    /// Finds the first match of `regex` after `(y, x)`, wrapping around
+    /// the end of the buffer. Returns the match start in character units.
+    fn find_forward(&self, regex: &Regex, y: usize, x: usize) -> Option<(usize, usize)> {
+        let rows = self.buffer.content.len();
+        for i in 0..=rows {
+            let row = (y + i) % rows;
+            let line = &self.buffer.content[row];
+            // Only look past the cursor on the first pass over its line
+            let start = if i == 0 { byte_offset(line, x + 1) } else { 0 };
+            if let Some((found, _)) = regex.find_at(line, start) {
+                return Some((row, line[..found].chars().count()));
+            }
+        }
+        None
+    }
+
+    /// Finds the last match of `regex` before `(y, x)`, wrapping around
+    /// the start of the buffer.
+    fn find_backward(&self, regex: &Regex, y: usize, x: usize) -> Option<(usize, usize)> {
+        let rows = self.buffer.content.len();
+        for i in 0..=rows {
+            let row = (y + rows * 2 - i) % rows;
+            let line = &self.buffer.content[row];
+            let end = if i == 0 { byte_offset(line, x) } else { line.len() };
+            // The last match starting before `end`; it may extend past it
+            let mut found = None;
+            let mut from = 0;
+            while let Some((b, _)) = regex.find_at(line, from) {
+                if b >= end {
+                    break;
+                }
+                found = Some(b);
+                from = b + line[b..].chars().next().map_or(1, char::len_utf8);
+            }
+            if let Some(b) = found {
+                return Some((row, line[..b].chars().count()));
+            }
+        }
+        None
+    }
+
+    /// Executes an ex command typed after `:`.
+    fn run_command(&mut self, cmd: &str) -> crossterm::Result<()> {
+        let (range, cmd) = match self.parse_range(cmd) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                self.status_message = Some(message);
+                return Ok(());
+            }
+        };
+        if let Some(spec) = cmd.strip_prefix('s') {
+            if spec.starts_with(|c: char| !c.is_alphanumeric() && !c.is_whitespace()) {
+                let (first, last) = range.unwrap_or((self.buffer.cursor_y, self.buffer.cursor_y));
+                self.substitute(first, last, spec);
+                return Ok(());
+            }
+        }
+        let delimited = |spec: &str| spec.starts_with(|c: char| !c.is_alphanumeric() && !c.is_whitespace());
+        for (prefix, invert) in [("g!", true), ("g", false), ("v", true)] {
+            if let Some(spec) = cmd.strip_prefix(prefix).filter(|spec| delimited(spec)) {
+                let (first, last) = range.unwrap_or((0, self.buffer.content.len() - 1));
+                return self.global(first, last, spec, invert);
+            }
+        }
+        if let "d" | "delete" = cmd {
+            let (first, last) = range.unwrap_or((self.buffer.cursor_y, self.buffer.cursor_y));
+            self.buffer.cursor_y = first;
+            let text = self.delete_lines(last - first + 1);
+            self.set_register(Register { text, linewise: true });
+            return Ok(());
+        }
+        let sort = cmd
+            .strip_prefix("sort")
+            .or_else(|| cmd.strip_prefix("sor"))
+            .filter(|rest| rest.is_empty() || rest.starts_with(['!', ' ']));
+        if let Some(rest) = sort {
+            let (first, last) = range.unwrap_or((0, self.buffer.content.len() - 1));
+            match rest.strip_prefix('!') {
+                Some(flags) => self.sort_lines(first, last, true, flags.trim()),
+                None => self.sort_lines(first, last, false, rest.trim()),
+            }
+            return Ok(());
+        }
+        if let Some((first, last)) = range {
+            match cmd.strip_prefix('!') {
+                Some(command) => self.filter_lines(first, last, command.trim()),
+                None if cmd.is_empty() => {
+                    self.push_jump();
+                    self.goto_line(last);
+                }
+                None => self.status_message = Some("No range allowed".to_string()),
+            }
+            return Ok(());
+        }
+        let (name, arg) = match cmd.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (cmd, None),
+        };
+        match name {
+            "" => {}
+            "noh" | "nohlsearch" => self.highlight_search = false,
+            "wc" => self.word_count(),
+            "set" | "se" => {
+                for option in arg.unwrap_or("").split_whitespace() {
+                    if let Err(message) = self.set_option(option) {
+                        self.status_message = Some(message);
+                        break;
+                    }
+                }
+            }
+            "w" | "w!" | "wq" | "x" => {
+                if self.write_buffer(name == "w!", arg.map(Path::new)) && matches!(name, "wq" | "x") {
+                    if !self.any_modified() {
+                        return self.exit();
+                    }
+                    // Writing a copy leaves this buffer's own changes unsaved
+                    let which = if self.buffer.modified { "This" } else { "Another" };
+                    self.status_message =
+                        Some(format!("{} buffer has unsaved changes (use :q! to discard)", which));
+                }
+            }
+            "r" | "read" => match arg {
+                Some(arg) => match arg.strip_prefix('!') {
+                    Some(command) => self.read_command(command),
+                    None => self.read_file(Path::new(arg)),
+                },
+                // As in vim, the buffer's own file on disk
+                None => match self.buffer.filename.clone() {
+                    Some(path) => self.read_file(&path),
+                    None => self.status_message = Some("No file name".to_string()),
+                },
+            },
+            _ if name.starts_with("r!") => self.read_command(&cmd[2..]),
+            "e" | "edit" | "e!" | "edit!" => match arg {
+                Some(path) => {
+                    if let Err(e) = self.edit(PathBuf::from(path)) {
+                        self.status_message = Some(format!("Error opening file: {}", e));
+                    }
+                }
+                None if self.buffer.modified && !name.ends_with('!') => {
+                    self.status_message =
+                        Some("No write since last change (add ! to override)".to_string());
+                }
+                None => self.reload(),
+            },
+            "rec" | "recover" | "rec!" | "recover!" => self.recover(name.ends_with('!')),
+            "fin" | "find" => return self.find_file(arg.unwrap_or("")),
+            "ol" | "oldfiles" => return self.old_files(),
+            "mks" | "mksession" | "mks!" | "mksession!" => {
+                self.make_session(arg, name.ends_with('!'))
+            }
+            "so" | "source" => self.load_session(Path::new(arg.unwrap_or(session::DEFAULT_FILE))),
+            "sp" | "split" => self.split_window(false, arg),
+            "vs" | "vsplit" => self.split_window(true, arg),
+            "clo" | "close" => self.close_window(),
+            "on" | "only" => self.only_window(),
+            "bn" | "bnext" => self.cycle_buffer(true),
+            "bp" | "bprevious" => self.cycle_buffer(false),
+            "q" | "q!" if self.windows.len() > 1 => self.close_window(),
+            "q" if self.any_modified() => {
+                self.status_message =
+                    Some("No write since last change (add ! to override)".to_string());
+            }
+            "q" | "q!" => return self.exit(),
+            _ => self.status_message = Some(format!("Not an editor command: {}", cmd)),
+        }
+        Ok(())
+    }
+
+    /// `:g/pattern/cmd` runs the ex command `cmd` on each line of
+    /// `first..=last` containing `pattern`, or with `invert` (`:g!`, `:v`)
+    /// on each line without it. Matches are found up front and visited last
+    /// to first, so commands that add or delete lines leave the rest in
+    /// place. Without a command the cursor goes to the last match.
+    fn global(&mut self, first: usize, last: usize, spec: &str, invert: bool) -> crossterm::Result<()> {
+        let mut chars = spec.chars();
+        let delimiter = chars.next().unwrap();
+        let mut pattern = String::new();
+        let mut escaped = false;
+        let mut command = "";
+        while let Some(c) = chars.next() {
+            if escaped {
+                if c != delimiter {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                command = chars.as_str();
+                break;
+            } else {
+                pattern.push(c);
+            }
+        }
+        if pattern.is_empty() {
+            match &self.last_search {
+                Some(last) => pattern = last.clone(),
+                None => {
+                    self.status_message = Some("No previous search pattern".to_string());
+                    return Ok(());
+                }
+            }
+        }
+        let regex = match self.compile_pattern(&pattern, false) {
+            Ok(regex) => regex,
+            Err(message) => {
+                self.status_message = Some(format!("Invalid pattern: {}", message));
+                return Ok(());
+            }
+        };
+        // As in vim, the pattern becomes the last search, so `n` and an
+        // empty `s//` in the command find it
+        self.last_search = Some(pattern.clone());
+        self.highlight_search = true;
+        let matches: Vec<usize> = (first..=last)
+            .filter(|&y| regex.find_at(&self.buffer.content[y], 0).is_some() != invert)
+            .collect();
+        let Some(&last_match) = matches.last() else {
+            self.status_message = Some(format!("Pattern not found: {}", pattern));
+            return Ok(());
+        };
+        let command = command.trim();
+        if command.is_empty() {
+            self.goto_line(last_match);
+            self.buffer.cursor_x = self.first_non_blank(last_match);
+            self.status_message = Some(format!("{} matching lines", matches.len()));
+            return Ok(());
+        }
+        let before = self.snapshot();
+        let tick = self.change_tick;
+        let mut result = Ok(());
+        for &y in matches.iter().rev() {
+            self.buffer.cursor_y = std::cmp::min(y, self.buffer.content.len() - 1);
+            self.buffer.cursor_x = 0;
+            result = self.run_command(command);
+            if result.is_err() {
+                break;
+            }
+        }
+        // Fold the undo steps of each command into one for the whole `:g`
+        let steps = self.change_tick - tick;
+        if steps > 0 {
+            let kept = self.buffer.undo_stack.len().saturating_sub(steps);
+            self.buffer.undo_stack.truncate(kept);
+            self.push_undo(before);
+        }
+        result
+    }
+
+    /// Splits the line range off the front of an ex command: `%` for the
+    /// whole buffer, or one or two comma-separated addresses. Lines are
+    /// 0-based and inclusive.
+    fn parse_range<'a>(&self, cmd: &'a str) -> Result<(Option<LineRange>, &'a str), String> {
+        let last_line = self.buffer.content.len() - 1;
+        if let Some(rest) = cmd.strip_prefix('%') {
+            return Ok((Some((0, last_line)), rest));
+        }
+        let Some((first, rest)) = self.parse_address(cmd)? else {
+            return Ok((None, cmd));
+        };
+        let (last, rest) = match rest.strip_prefix(',') {
+            Some(rest) => self.parse_address(rest)?.ok_or("Invalid range")?,
+            None => (first, rest),
+        };
+        let (first, last) = (std::cmp::min(first, last_line), std::cmp::min(last, last_line));
+        Ok((Some((std::cmp::min(first, last), std::cmp::max(first, last))), rest))
+    }
+
+    /// Parses a line number, `.` for the cursor line, `$` for the last line
+    /// or `'x` for the line of mark `x` from the front of `s`.
+    fn parse_address<'a>(&self, s: &'a str) -> Result<Option<(usize, &'a str)>, String> {
+        let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 {
+            let line: usize = s[..digits].parse().map_err(|_| "Invalid range".to_string())?;
+            return Ok(Some((line.saturating_sub(1), &s[digits..])));
+        }
+        let mut chars = s.chars();
+        let line = match chars.next() {
+            Some('.') => self.buffer.cursor_y,
+            Some('$') => self.buffer.content.len() - 1,
+            Some('\'') => {
+                let name = chars.next().ok_or("Invalid range")?;
+                let &(y, _) = self.buffer.marks.get(&name).ok_or(format!("Mark {} not set", name))?;
+                y
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some((line, chars.as_str())))
+    }
+
+    /// `:{range}!cmd`: pipes lines `first..=last` through the shell command
+    /// and replaces them with what it prints. A failing command leaves the
+    /// lines alone.
+    fn filter_lines(&mut self, first: usize, last: usize, command: &str) {
+        let input: String = self.buffer.content
+            .range(first..=last)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let output = match run_shell(command, Some(&input)) {
+            Ok(output) => output,
+            Err(e) => {
+                self.status_message = Some(format!("Cannot run shell: {}", e));
+                return;
+            }
+        };
+        if let Some(message) = shell_failure(&output) {
+            self.status_message = Some(message);
+            return;
+        }
+        let lines: Vec<String> = String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect();
+        let removed = last - first + 1;
+        let added = lines.len();
+        self.checkpoint();
+        self.buffer.content.splice(first..=last, lines);
+        self.shift_marks(first, -(removed as isize));
+        self.shift_marks(first, added as isize);
+        if self.buffer.content.is_empty() {
+            self.buffer.content.push(String::new());
+        }
+        self.buffer.cursor_y = std::cmp::min(first, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = self.first_non_blank(self.buffer.cursor_y);
+        self.buffer.modified = true;
+        self.status_message = Some(format!("{} lines filtered", removed));
+    }
+
+    /// `:sort` over lines `first..=last`. `flags` may hold `n` to order by
+    /// the first number in each line and `i` to ignore case; lines without
+    /// a number go first, in their original order.
+    fn sort_lines(&mut self, first: usize, last: usize, reverse: bool, flags: &str) {
+        let (mut numeric, mut ignore_case) = (false, false);
+        for flag in flags.chars().filter(|c| !c.is_whitespace()) {
+            match flag {
+                'n' => numeric = true,
+                'i' => ignore_case = true,
+                _ => {
+                    self.status_message = Some(format!("Invalid argument: {}", flags));
+                    return;
+                }
+            }
+        }
+        let mut lines: Vec<String> = self.buffer.content.range(first..=last).cloned().collect();
+        // Equal lines keep their order, reversed or not
+        lines.sort_by(|a, b| {
+            let order = if numeric {
+                first_number(a).cmp(&first_number(b))
+            } else if ignore_case {
+                a.to_lowercase().cmp(&b.to_lowercase())
+            } else {
+                a.cmp(b)
+            };
+            if reverse {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+        if lines.iter().eq(self.buffer.content.range(first..=last)) {
+            return;
+        }
+        self.checkpoint();
+        self.buffer.content.splice(first..=last, lines);
+        self.goto_line(self.buffer.cursor_y);
+        self.buffer.modified = true;
+    }
+
+    /// `:r file`: inserts the contents of `path` below the cursor line.
+    fn read_file(&mut self, path: &Path) {
+        match fs::read_to_string(path) {
+            Ok(text) => self.insert_below(&text),
+            Err(e) => self.status_message = Some(format!("Error reading file: {}", e)),
+        }
+    }
+
+    /// `:r !cmd`: inserts what shell `command` prints below the cursor line,
+    /// if it succeeds.
+    fn read_command(&mut self, command: &str) {
+        match run_shell(command.trim(), None) {
+            // A failed command's output may be partial or an error message,
+            // neither of which belongs in the file
+            Ok(output) => match shell_failure(&output) {
+                Some(failure) => self.status_message = Some(failure),
+                None => self.insert_below(&String::from_utf8_lossy(&output.stdout)),
+            },
+            Err(e) => self.status_message = Some(format!("Cannot run shell: {}", e)),
+        }
+    }
+
+    /// Inserts the lines of `text` below the cursor line as one undo step,
+    /// leaving the cursor at the start of them.
+    fn insert_below(&mut self, text: &str) {
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        if lines.is_empty() {
+            return;
+        }
+        self.checkpoint();
+        let y = self.buffer.cursor_y + 1;
+        let added = lines.len() as isize;
+        self.buffer.content.splice(y..y, lines);
+        self.shift_marks(y, added);
+        self.buffer.cursor_y = y;
+        self.buffer.cursor_x = self.first_non_blank(y);
+        self.buffer.modified = true;
+    }
+
+    /// Applies one `:set` argument: `name`, `noname`, `name!` (toggle) or
+    /// `name=value`.
+    fn set_option(&mut self, option: &str) -> Result<(), String> {
+        if let Some((name, value)) = option.split_once('=') {
+            if let "filetype" | "ft" = name {
+                self.set_filetype(value);
+                return Ok(());
+            }
+            if let "fileformat" | "ff" = name {
+                let ending = match value {
+                    "unix" => LineEnding::Unix,
+                    "dos" => LineEnding::Dos,
+                    _ => return Err(format!("Invalid argument: {}", option)),
+                };
+                if ending != self.buffer.line_ending {
+                    self.buffer.line_ending = ending;
+                    self.buffer.modified = true;
+                }
+                return Ok(());
+            }
+            let number = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid argument: {}", option))?;
+            match name {
+                "tabstop" | "ts" if number > 0 => {
+                    self.tab_width = number;
+                    self.buffer.tab_width = Some(number);
+                    self.buffer.tab_width_set = true;
+                }
+                "colorcolumn" | "cc" => self.colorcolumn = number,
+                "autosave" => self.autosave = number,
+                "scrolloff" | "so" => self.scrolloff = number,
+                "sidescrolloff" | "siso" => self.sidescrolloff = number,
+                _ => return Err(format!("Invalid argument: {}", option)),
+            }
+            return Ok(());
+        }
+
+        let (name, value) = if let Some(name) = option.strip_suffix('!') {
+            (name, None)
+        } else if let Some(name) = option.strip_prefix("no") {
+            (name, Some(false))
+        } else {
+            (option, Some(true))
+        };
+        match (name, value) {
+            ("colorcolumn" | "cc", Some(false)) => {
+                self.colorcolumn = 0;
+                return Ok(());
+            }
+            ("autosave", Some(false)) => {
+                self.autosave = 0;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if let "expandtab" | "et" = name {
+            let expandtab = value.unwrap_or(!self.expandtab());
+            self.expandtab = expandtab;
+            self.buffer.expandtab = Some(expandtab);
+            self.buffer.expandtab_set = true;
+            return Ok(());
+        }
+        let flag = match name {
+            "autoindent" | "ai" => &mut self.autoindent,
+            "autopairs" => &mut self.autopairs,
+            "trim_trailing_whitespace" => &mut self.trim_trailing_whitespace,
+            "insert_final_newline" => &mut self.insert_final_newline,
+            "cursorline" | "cul" => &mut self.cursorline,
+            "highlight_trailing_whitespace" => &mut self.highlight_trailing_whitespace,
+            "undofile" | "udf" => &mut self.undofile,
+            "regex" => &mut self.regex,
+            "relativenumber" | "rnu" => &mut self.relative_numbers,
+            "number" | "nu" => &mut self.show_line_numbers,
+            "scrollbar" => &mut self.scrollbar,
+            "list" => &mut self.list,
+            "clipboard" => &mut self.clipboard,
+            _ => return Err(format!("Unknown option: {}", name)),
+        };
+        *flag = value.unwrap_or(!*flag);
+        Ok(())
+    }
+
+    /// Runs `:s` over lines `first..=last`. `spec` is everything after the
+    /// `s`, e.g. `/foo/bar/gi`.
+    fn substitute(&mut self, first: usize, last: usize, spec: &str) {
+        let (pattern, replacement, flags) = match parse_substitute(spec) {
+            Some(parts) => parts,
+            None => {
+                self.status_message = Some("Invalid substitute command".to_string());
+                return;
+            }
+        };
+        let pattern = if pattern.is_empty() {
+            match &self.last_search {
+                Some(last) => last.clone(),
+                None => {
+                    self.status_message = Some("No previous search pattern".to_string());
+                    return;
+                }
+            }
+        } else {
+            pattern
+        };
+        let global = flags.contains('g');
+        let regex = match self.compile_pattern(&pattern, flags.contains('i')) {
+            Ok(regex) => regex,
+            Err(message) => {
+                self.status_message = Some(format!("Invalid pattern: {}", message));
+                return;
+            }
+        };
+        let before = self.snapshot();
+        let mut confirm = flags.contains('c');
+        let mut matched = false;
+        let mut quit = false;
+
+        let mut total = 0;
+        let mut lines = 0;
+        for y in first..=last {
+            if quit {
+                break;
+            }
+            let line = self.buffer.content[y].clone();
+            let mut result = String::with_capacity(line.len());
+            let mut from = 0;
+            let mut search_from = 0;
+            let mut count = 0;
+            while let Some(groups) = regex.captures_at(&line, search_from) {
+                let (start, end) = groups[0].unwrap();
+                matched = true;
+                let answer = if confirm {
+                    // Show the line as substituted so far, with this match picked out
+                    self.buffer.content[y] = format!("{}{}", result, &line[from..]);
+                    let x = result.chars().count() + line[from..start].chars().count();
+                    let width = line[start..end].chars().count();
+                    self.set_cursor((y, x));
+                    self.confirm_match = Some((y, x, x + width));
+                    let question = format!("replace with {} (y/n/a/q)?", replacement);
+                    let answer = self.ask(&question, &['y', 'n', 'a', 'q']).unwrap_or('q');
+                    self.confirm_match = None;
+                    self.buffer.content[y] = line.clone();
+                    answer
+                } else {
+                    'y'
+                };
+                match answer {
+                    'q' => {
+                        quit = true;
+                        break;
+                    }
+                    'n' => {}
+                    _ => {
+                        confirm &= answer != 'a';
+                        result.push_str(&line[from..start]);
+                        result.push_str(&regex.expand(&line, &groups, &replacement));
+                        from = end;
+                        count += 1;
+                    }
+                }
+                if !global {
+                    break;
+                }
+                // Step over a character after an empty match so it is not
+                // found again
+                search_from = match line[end..].chars().next() {
+                    Some(c) if start == end => end + c.len_utf8(),
+                    Some(_) => end,
+                    None => break,
+                };
+            }
+            if count > 0 {
+                result.push_str(&line[from..]);
+                self.buffer.content[y] = result;
+                total += count;
+                lines += 1;
+                self.buffer.cursor_y = y;
+            }
+        }
+
+        if total == 0 {
+            self.status_message = (!matched).then(|| format!("Pattern not found: {}", pattern));
+            self.set_cursor((before.cursor_y, before.cursor_x));
+            return;
+        }
+        self.push_undo(before);
+        self.buffer.modified = true;
+        self.buffer.cursor_x = self.first_non_blank(self.buffer.cursor_y);
+        self.last_search = Some(pattern);
+        self.status_message = Some(format!(
+            "{} substitution{} on {} line{}",
+            total,
+            if total == 1 { "" } else { "s" },
+            lines,
+            if lines == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn handle_insert_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        if let (KeyCode::Char(c @ ('n' | 'p')), true) = (event.code, control) {
+            self.complete(c == 'n');
+            return Ok(());
+        }
+        // Any other key keeps the completion shown and starts over next time
+        self.completion = None;
+        match event.code {
+            KeyCode::Esc => {
+                self.finish_block_insert();
+                self.mode = Mode::Normal;
+                self.undo_group_open = false;
+            }
+            KeyCode::Char(c) => self.insert_typed(c),
+            KeyCode::Enter => self.insert_newline(),
+            KeyCode::Tab if !self.expand_snippet() && !self.next_snippet_stop() => {
+                self.insert_tab()
+            }
+            KeyCode::Backspace => self.handle_backspace(),
+            KeyCode::Home => self.smart_home(true),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces the word before the cursor with the next (or previous)
+    /// buffer word it is a prefix of. Words are offered nearest first,
+    /// searching forward from the cursor and wrapping around, and cycling
+    /// past the last one comes back to what was typed.
+    fn complete(&mut self, forward: bool) {
+        let y = self.buffer.cursor_y;
+        let mut completion = match self.completion.take() {
+            Some(completion) => completion,
+            None => {
+                let chars: Vec<char> = self.buffer.content[y].chars().collect();
+                let x = std::cmp::min(self.buffer.cursor_x, chars.len());
+                let mut start = x;
+                while start > 0 && is_word_char(chars[start - 1]) {
+                    start -= 1;
+                }
+                let prefix: String = chars[start..x].iter().collect();
+                let words = self.completions(&prefix, y, x);
+                if words.is_empty() {
+                    self.status_message = Some("No matches".to_string());
+                    return;
+                }
+                Completion { start, prefix, words, index: None }
+            }
+        };
+        let len = completion.words.len();
+        let shown = completion.index.map_or(&completion.prefix, |i| &completion.words[i]);
+        let shown_len = shown.chars().count();
+        completion.index = match (completion.index, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(len - 1),
+            (Some(i), true) => (i + 1 < len).then_some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+        };
+        let word = completion.index.map_or(&completion.prefix, |i| &completion.words[i]);
+        self.checkpoint();
+        self.replace_at((y, completion.start), shown_len, word);
+        let word_len = word.chars().count();
+        let kept = completion.start + std::cmp::min(shown_len, word_len);
+        self.shift_snippet_stops(y, kept, word_len as isize - shown_len as isize);
+        self.buffer.cursor_x = completion.start + word.chars().count();
+        self.status_message = Some(match completion.index {
+            Some(i) => format!("Match {} of {}", i + 1, len),
+            None => "Back at original".to_string(),
+        });
+        self.completion = Some(completion);
+    }
+
+    /// Distinct words of the buffer starting with `prefix`, other than
+    /// `prefix` itself, in order of appearance from `(y, x)` onwards.
+    fn completions(&self, prefix: &str, y: usize, x: usize) -> Vec<String> {
+        let rows = self.buffer.content.len();
+        let mut seen = std::collections::HashSet::new();
+        let mut words = Vec::new();
+        for i in 0..=rows {
+            let row = (y + i) % rows;
+            let chars: Vec<char> = self.buffer.content[row].chars().collect();
+            // The cursor line is split in two: after the cursor first, the
+            // rest on the way back around
+            let (from, to) = match i {
+                0 => (x, chars.len()),
+                i if i == rows => (0, x),
+                _ => (0, chars.len()),
+            };
+            let mut col = from;
+            // A word cut by the cursor is not a candidate
+            while i == 0 && col < to && is_word_char(chars[col]) {
+                col += 1;
+            }
+            while col < to {
+                if !is_word_char(chars[col]) {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < chars.len() && is_word_char(chars[col]) {
+                    col += 1;
+                }
+                let word: String = chars[start..col].iter().collect();
+                if word.len() > prefix.len() && word.starts_with(prefix) && seen.insert(word.clone()) {
+                    words.push(word);
+                }
+            }
+        }
+        words
+    }
+
+    /// Typed characters overwrite the text under the cursor, or extend the
+    /// line at its end. Backspace brings back what was overwritten.
+    fn handle_replace_mode(&mut self, event: KeyEvent) -> crossterm::Result<()> {
+        match event.code {
+            KeyCode::Esc => {
+                self.mode = Mode::Normal;
+                self.undo_group_open = false;
+            }
+            KeyCode::Char(c) => {
+                self.checkpoint();
+                let (x, y) = (self.buffer.cursor_x, self.buffer.cursor_y);
+                let line = &mut self.buffer.content[y];
+                let at = byte_offset(line, x);
+                let old = line[at..].chars().next();
+                match old {
+                    Some(old) => line.replace_range(at..at + old.len_utf8(), &c.to_string()),
+                    None => line.push(c),
+                }
+                self.replaced.push(old);
+                self.buffer.cursor_x += 1;
+                self.buffer.modified = true;
+            }
+            KeyCode::Enter => {
+                self.replaced.clear();
+                self.insert_newline();
+            }
+            KeyCode::Backspace if self.buffer.cursor_x > 0 => {
+                self.buffer.cursor_x -= 1;
+                let Some(old) = self.replaced.pop() else {
+                    return Ok(());
+                };
+                let line = &mut self.buffer.content[self.buffer.cursor_y];
+                let at = byte_offset(line, self.buffer.cursor_x);
+                let typed = line[at..].chars().next().map_or(0, char::len_utf8);
+                match old {
+                    Some(old) => line.replace_range(at..at + typed, &old.to_string()),
+                    None => line.replace_range(at..at + typed, ""),
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Length of a line in characters, which is the unit `cursor_x` counts in.
+    fn line_len(&self, y: usize) -> usize {
+        self.buffer.content[y].chars().count()
+    }
+
+    /// Prints the visible part of line `y`, starting at `col_offset`, with
+    /// tabs expanded and matches of `search` and `brackets` highlighted; the
+    /// stored line is left untouched.
+    fn draw_line(
+        &self,
+        stdout: &mut Stdout,
+        y: usize,
+        brackets: Option<[(usize, usize); 2]>,
+        selection: Option<Selection>,
+        search: Option<&Regex>,
+    ) -> crossterm::Result<()> {
+        let line = &self.buffer.content[y];
+        // The cursor line tint sits under every other highlight
+        let background = if self.cursorline && y == self.buffer.cursor_y {
+            self.theme.cursor_line_bg
+        } else {
+            Color::Reset
+        };
+        let mut colors = vec![Colors::new(Color::Reset, background); line.chars().count()];
+        let mut attributes = vec![None; colors.len()];
+        let highlights = match self.buffer.filetype.as_str() {
+            "rust" => syntax::highlight_rust(line),
+            "markdown" => syntax::highlight_markdown(line, self.in_code_fence(y)),
+            _ => Vec::new(),
+        };
+        for ((color, attribute), highlight) in colors.iter_mut().zip(&mut attributes).zip(highlights) {
+            if let Some(fg) = highlight.color() {
+                color.foreground = Some(fg);
+            }
+            *attribute = highlight.attribute();
+        }
+        if self.highlight_trailing_whitespace {
+            // Spaces and tabs are one byte each, so the byte count is the char count
+            let trailing = line.len() - line.trim_end_matches([' ', '\t']).len();
+            let start = colors.len() - trailing;
+            for color in &mut colors[start..] {
+                color.background = Some(self.theme.trailing_whitespace_bg);
+            }
+        }
+        if let Some(regex) = search {
+            for (start, end) in match_ranges(line, regex) {
+                for color in &mut colors[start..end] {
+                    *color = Colors::new(Color::Black, Color::Yellow);
+                }
+            }
+        }
+        if let Some((my, start, end)) = self.confirm_match {
+            if my == y {
+                for color in &mut colors[start..end] {
+                    *color = Colors::new(Color::Black, Color::Magenta);
+                }
+            }
+        }
+        for &(by, bx) in brackets.iter().flatten() {
+            if by == y {
+                colors[bx] = Colors::new(Color::Black, Color::DarkCyan);
+            }
+        }
+        if let Some(selection) = selection {
+            if let Some((start, end)) = selection.columns(y, colors.len()) {
+                for color in &mut colors[start..end] {
+                    *color = Colors::new(Color::Black, Color::Grey);
+                }
+            }
+        }
+
+        let first = self.buffer.col_offset;
+        let last = self.buffer.col_offset + self.text_cols();
+        // The ruler only tints cells no other highlight has claimed
+        let ruler = self.colorcolumn.checked_sub(1).filter(|r| (first..last).contains(r));
+        let ruler_color = Colors::new(Color::Reset, self.theme.color_column_bg);
+        let list = if self.list {
+            self.list_chars
+        } else {
+            ListChars { space: None, tab: None, eol: None }
+        };
+        let mut col = 0;
+        let mut current = None;
+        let mut current_attribute = None;
+        // Whether the cluster being drawn was cut by the left edge, so the
+        // rest of it is left out too
+        let mut cut = false;
+        let cells = line.chars().zip(self.cell_widths(line));
+        for (((c, width), mut color), attribute) in cells.zip(colors).zip(attributes) {
+            let (start, end) = (col, col + width);
+            col = end;
+            if width > 0 {
+                cut = start < first;
+            }
+            if end <= first || (width == 0 && cut) {
+                continue;
+            }
+            if start >= last {
+                break;
+            }
+            let ruled = matches!(ruler, Some(r) if start <= r && r < end)
+                && color.background == Some(background);
+            if ruled && c != '\t' {
+                color.background = ruler_color.background;
+            }
+            let glyph = match c {
+                ' ' => list.space,
+                '\t' => list.tab,
+                _ => None,
+            };
+            if glyph.is_some() && color.foreground == Some(Color::Reset) {
+                color.foreground = Some(self.theme.list_fg);
+            }
+            if current_attribute != attribute {
+                // Turning bold or italic off leaves the colors alone
+                match current_attribute {
+                    Some(Attribute::Italic) => queue!(stdout, style::SetAttribute(Attribute::NoItalic))?,
+                    Some(_) => queue!(stdout, style::SetAttribute(Attribute::NormalIntensity))?,
+                    None => {}
+                }
+                if let Some(attribute) = attribute {
+                    queue!(stdout, style::SetAttribute(attribute))?;
+                }
+                current_attribute = attribute;
+            }
+            if current != Some(color) {
+                queue!(stdout, style::SetColors(color))?;
+                current = Some(color);
+            }
+            if c == '\t' || start < first || end > last {
+                // A tab or wide character straddling either edge only shows
+                // its visible cells
+                let (from, to) = (std::cmp::max(start, first), std::cmp::min(end, last));
+                let cells: Vec<char> = match glyph {
+                    Some(glyph) => {
+                        std::iter::once(glyph).chain(std::iter::repeat(' ')).take(width).collect()
+                    }
+                    None => vec![' '; width],
+                };
+                let shown =
+                    |from: usize, to: usize| cells[from - start..to - start].iter().collect::<String>();
+                match ruler.filter(|_| ruled && c == '\t') {
+                    Some(r) => queue!(
+                        stdout,
+                        style::Print(shown(from, r)),
+                        style::SetColors(Colors { background: ruler_color.background, ..color }),
+                        style::Print(shown(r, r + 1)),
+                        style::SetColors(color),
+                        style::Print(shown(r + 1, to)),
+                    )?,
+                    None => queue!(stdout, style::Print(shown(from, to)))?,
+                }
+            } else {
+                queue!(stdout, style::Print(glyph.unwrap_or(c)))?;
+            }
+        }
+        if current_attribute.is_some() {
+            queue!(stdout, style::SetAttribute(Attribute::Reset))?;
+        }
+        let mut printed = std::cmp::max(std::cmp::min(col, last), first);
+        if let Some(eol) = list.eol.filter(|_| (first..last).contains(&col)) {
+            let background = if ruler == Some(col) {
+                ruler_color.background
+            } else {
+                Some(background)
+            };
+            queue!(
+                stdout,
+                style::SetColors(Colors { foreground: Some(self.theme.list_fg), background }),
+                style::Print(eol),
+            )?;
+            printed = col + 1;
+        }
+        if let Some(r) = ruler.filter(|&r| r >= printed) {
+            // Short lines reach the ruler with blanks
+            queue!(
+                stdout,
+                style::SetColors(Colors::new(Color::Reset, background)),
+                style::Print(" ".repeat(r - printed)),
+                style::SetColors(ruler_color),
+                style::Print(" "),
+            )?;
+            printed = r + 1;
+        }
+        if background != Color::Reset {
+            // Carry the tint on to the edge of the window
+            queue!(
+                stdout,
+                style::SetColors(Colors::new(Color::Reset, background)),
+                style::Print(" ".repeat(last - printed)),
+            )?;
+        }
+        queue!(stdout, style::SetColors(Colors::new(Color::Reset, Color::Reset)))?;
+        Ok(())
+    }
+
+    /// Whether line `y` of a Markdown buffer sits between code fences.
+    fn in_code_fence(&self, y: usize) -> bool {
+        let fences = self.buffer.content.range(..y).filter(|line| syntax::is_code_fence(line)).count();
+        fences % 2 == 1
+    }
+
+    /// The pattern to highlight: the one being typed after `/`, or the last
+    /// search until it is dismissed.
+    fn highlight_pattern(&self) -> Option<&str> {
+        match self.mode {
+            Mode::Search if !self.command_buffer.is_empty() => Some(&self.command_buffer),
+            Mode::Search => None,
+            _ if self.highlight_search => self.last_search.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Screen column of character `x` on line `y` once tabs are expanded.
+    fn display_col(&self, y: usize, x: usize) -> usize {
+        self.cell_widths(&self.buffer.content[y]).iter().take(x).sum()
+    }
+
+    /// Screen cells taken by each character of `line`: a tab reaches the
+    /// next tab stop, and a grapheme cluster's width goes on its first
+    /// character, so wide characters take two and combining marks none.
+    fn cell_widths(&self, line: &str) -> Vec<usize> {
+        let mut col = 0;
+        line.chars()
+            .zip(unicode::widths(line))
+            .map(|(c, width)| {
+                let width = if c == '\t' { self.tab_width() - col % self.tab_width() } else { width };
+                col += width;
+                width
+            })
+            .collect()
+    }
+
+    /// Moves to the first non-blank of the line, or to column 0 if already
+    /// there; `indent_first` false tries column 0 first. A blank line only
+    /// has column 0.
+    fn smart_home(&mut self, indent_first: bool) {
+        let y = self.buffer.cursor_y;
+        let indent = if self.buffer.content[y].trim().is_empty() { 0 } else { self.first_non_blank(y) };
+        let (first, second) = if indent_first { (indent, 0) } else { (0, indent) };
+        self.buffer.cursor_x = if self.buffer.cursor_x == first { second } else { first };
+    }
+
+    /// Index of the first non-whitespace character on line `y`, or the line
+    /// length if it is all whitespace.
+    fn first_non_blank(&self, y: usize) -> usize {
+        self.buffer.content[y]
+            .chars()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or_else(|| self.line_len(y))
+    }
+
+    /// Classifies the character at `(y, x)` for word motions. The position
+    /// just past the end of a line stands for the line break.
+    fn char_class(&self, y: usize, x: usize) -> CharClass {
+        match self.buffer.content[y].chars().nth(x) {
+            Some(c) if c.is_whitespace() => CharClass::Blank,
+            Some(c) if is_word_char(c) => CharClass::Word,
+            Some(_) => CharClass::Punct,
+            None if x == 0 => CharClass::EmptyLine,
+            None => CharClass::Blank,
+        }
+    }
+
+    /// The position after `(y, x)`, treating each line break as one position.
+    fn next_pos(&self, y: usize, x: usize) -> Option<(usize, usize)> {
+        if x < self.line_len(y) {
+            Some((y, x + 1))
+        } else if y + 1 < self.buffer.content.len() {
+            Some((y + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    fn prev_pos(&self, y: usize, x: usize) -> Option<(usize, usize)> {
+        if x > 0 {
+            Some((y, x - 1))
+        } else if y > 0 {
+            Some((y - 1, self.line_len(y - 1)))
+        } else {
+            None
+        }
+    }
+
+    /// Target of `w`: the start of the next word, crossing lines as needed.
+    fn next_word_start(&self, y: usize, x: usize) -> (usize, usize) {
+        let start = self.char_class(y, x);
+        let mut pos = (y, x);
+        if start != CharClass::Blank {
+            loop {
+                match self.next_pos(pos.0, pos.1) {
+                    Some(next) => pos = next,
+                    None => return self.last_char_pos(),
+                }
+                if start == CharClass::EmptyLine || self.char_class(pos.0, pos.1) != start {
+                    break;
+                }
+            }
+        }
+        while self.char_class(pos.0, pos.1) == CharClass::Blank {
+            match self.next_pos(pos.0, pos.1) {
+                Some(next) => pos = next,
+                None => return self.last_char_pos(),
+            }
+        }
+        pos
+    }
+
+    /// Target of `e`: the last character of the current or next word.
+    fn word_end(&self, y: usize, x: usize) -> (usize, usize) {
+        let mut pos = match self.next_pos(y, x) {
+            Some(next) => next,
+            None => return (y, x),
+        };
+        while matches!(self.char_class(pos.0, pos.1), CharClass::Blank | CharClass::EmptyLine) {
+            match self.next_pos(pos.0, pos.1) {
+                Some(next) => pos = next,
+                None => return self.last_char_pos(),
+            }
+        }
+        let class = self.char_class(pos.0, pos.1);
+        while let Some(next) = self.next_pos(pos.0, pos.1) {
+            if self.char_class(next.0, next.1) != class {
+                break;
+            }
+            pos = next;
+        }
+        pos
+    }
+
+    /// Target of `b`: the start of the current or previous word.
+    fn prev_word_start(&self, y: usize, x: usize) -> (usize, usize) {
+        let mut pos = match self.prev_pos(y, x) {
+            Some(prev) => prev,
+            None => return (y, x),
+        };
+        while self.char_class(pos.0, pos.1) == CharClass::Blank {
+            match self.prev_pos(pos.0, pos.1) {
+                Some(prev) => pos = prev,
+                None => return pos,
+            }
+        }
+        let class = self.char_class(pos.0, pos.1);
+        if class == CharClass::EmptyLine {
+            return pos;
+        }
+        while let Some(prev) = self.prev_pos(pos.0, pos.1) {
+            if self.char_class(prev.0, prev.1) != class {
+                break;
+            }
+            pos = prev;
+        }
+        pos
+    }
+
+    fn set_cursor(&mut self, (y, x): (usize, usize)) {
+        self.buffer.cursor_y = y;
+        self.buffer.cursor_x = x;
+    }
+
+    fn last_char_pos(&self) -> (usize, usize) {
+        let y = self.buffer.content.len() - 1;
+        (y, self.line_len(y).saturating_sub(1))
+    }
+
+    /// Target of `%`: the partner of the bracket under the cursor, or of the
+    /// first bracket after it on the line. Unbalanced brackets go nowhere.
+    fn jump_to_match(&mut self) {
+        let y = self.buffer.cursor_y;
+        let start = self.buffer.cursor_x;
+        let brackets = ['(', ')', '[', ']', '{', '}'];
+        let Some(x) = self.buffer.content[y]
+            .chars()
+            .enumerate()
+            .skip(start)
+            .find_map(|(x, c)| brackets.contains(&c).then_some(x))
+        else {
+            return;
+        };
+        if let Some(pos) = self.matching_bracket(y, x) {
+            self.set_cursor(pos);
+        }
+    }
+
+    /// Finds the partner of the bracket at `(y, x)` by scanning forward or
+    /// backward and tracking nesting depth. `None` if the character is not a
+    /// bracket or it is unmatched.
+    fn matching_bracket(&self, y: usize, x: usize) -> Option<(usize, usize)> {
+        let c = self.buffer.content[y].chars().nth(x)?;
+        let (open, close, forward) = match c {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => ('(', ')', false),
+            ']' => ('[', ']', false),
+            '}' => ('{', '}', false),
+            _ => return None,
+        };
+        let mut depth = 0usize;
+        let mut row = y;
+        loop {
+            let chars: Vec<char> = self.buffer.content[row].chars().collect();
+            let cols: Box<dyn Iterator<Item = usize>> = match (row == y, forward) {
+                (true, true) => Box::new(x + 1..chars.len()),
+                (true, false) => Box::new((0..x).rev()),
+                (false, true) => Box::new(0..chars.len()),
+                (false, false) => Box::new((0..chars.len()).rev()),
+            };
+            for col in cols {
+                let ch = chars[col];
+                if ch == c {
+                    depth += 1;
+                } else if ch == if forward { close } else { open } {
+                    if depth == 0 {
+                        return Some((row, col));
+                    }
+                    depth -= 1;
+                }
+            }
+            if forward {
+                row += 1;
+                if row == self.buffer.content.len() {
+                    return None;
+                }
+            } else {
+                row = row.checked_sub(1)?;
+            }
+        }
+    }
+
+    /// Remembers the cursor position before a jump. Any entries Ctrl-O went
+    /// back past are dropped, and so is an older entry for the same line.
+    fn push_jump(&mut self) {
+        let jump = (self.current, self.buffer.cursor_y, self.buffer.cursor_x);
+        self.jumps.truncate(self.jump_index);
+        self.jumps.retain(|&(buffer, y, _)| (buffer, y) != (jump.0, jump.1));
+        if self.jumps.len() == JUMP_LIMIT {
+            self.jumps.remove(0);
+        }
+        self.jumps.push(jump);
+        self.jump_index = self.jumps.len();
+    }
+
+    /// Ctrl-O: goes back `count` entries in the jump list. Leaving the
+    /// newest position first records it, so Ctrl-I can come back.
+    fn jump_back(&mut self, count: usize) {
+        if self.jump_index == self.jumps.len() {
+            self.push_jump();
+            self.jump_index -= 1;
+        }
+        match self.jump_index.checked_sub(count) {
+            Some(index) => self.go_to_jump(index),
+            None => self.status_message = Some("At start of jump list".to_string()),
+        }
+    }
+
+    /// Ctrl-I: goes forward `count` entries again after Ctrl-O.
+    fn jump_forward(&mut self, count: usize) {
+        match self.jump_index.checked_add(count) {
+            Some(index) if index < self.jumps.len() => self.go_to_jump(index),
+            _ => self.status_message = Some("At end of jump list".to_string()),
+        }
+    }
+
+    fn go_to_jump(&mut self, index: usize) {
+        self.jump_index = index;
+        let (buffer, y, x) = self.jumps[index];
+        self.switch_buffer(buffer);
+        // The line may be gone if the buffer shrank since
+        self.goto_line(y);
+        self.buffer.cursor_x = std::cmp::min(x, self.line_len(self.buffer.cursor_y));
+    }
+
+    /// Moves to line `y` (0-based), clamped to the buffer.
+    fn goto_line(&mut self, y: usize) {
+        self.buffer.cursor_y = std::cmp::min(y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(self.buffer.cursor_y));
+    }
+
+    /// Scrolls so the cursor line has `above` rows of the window above it,
+    /// or as many as there are lines for, closed folds taking one row.
+    fn scroll_cursor_to(&mut self, above: usize) {
+        let mut top = self.visible_start(self.buffer.cursor_y);
+        for _ in 0..above {
+            if top == 0 {
+                break;
+            }
+            top = self.visible_start(top - 1);
+        }
+        self.buffer.row_offset = top;
+    }
+
+    /// Scrolls forward a screen, keeping two lines of overlap like vim.
+    fn page_down(&mut self) {
+        let last = self.buffer.content.len() - 1;
+        let step = self.text_rows().saturating_sub(2).max(1);
+        self.buffer.row_offset = std::cmp::min(self.buffer.row_offset + step, last);
+        self.goto_line(self.buffer.row_offset + self.scroll_margin());
+    }
+
+    /// Scrolls back a screen, keeping two lines of overlap like vim.
+    fn page_up(&mut self) {
+        let rows = self.text_rows();
+        let step = rows.saturating_sub(2).max(1);
+        self.buffer.row_offset = self.buffer.row_offset.saturating_sub(step);
+        self.goto_line(self.buffer.row_offset + rows - 1 - self.scroll_margin());
+    }
+
+    /// Scrolls the view and the cursor by half a screen (Ctrl-D / Ctrl-U).
+    fn half_page(&mut self, down: bool) {
+        let last = self.buffer.content.len() - 1;
+        let step = std::cmp::max(self.text_rows() / 2, 1);
+        if down {
+            self.buffer.row_offset = std::cmp::min(self.buffer.row_offset + step, last);
+            self.goto_line(std::cmp::min(self.buffer.cursor_y + step, last));
+        } else {
+            self.buffer.row_offset = self.buffer.row_offset.saturating_sub(step);
+            self.goto_line(self.buffer.cursor_y.saturating_sub(step));
+        }
+    }
+
+    /// The character on line `y` covering screen column `col`, or the line
+    /// length when `col` is past the end of the line.
+    fn col_to_char(&self, y: usize, col: usize) -> usize {
+        let mut end = 0;
+        for (i, width) in self.cell_widths(&self.buffer.content[y]).into_iter().enumerate() {
+            end += width;
+            if end > col {
+                return i;
+            }
+        }
+        self.line_len(y)
+    }
+
+    fn move_cursor_left(&mut self) {
+        let line = &self.buffer.content[self.buffer.cursor_y];
+        self.buffer.cursor_x = unicode::prev_boundary(line, self.buffer.cursor_x);
+    }
+
+    fn move_cursor_right(&mut self) {
+        let line = &self.buffer.content[self.buffer.cursor_y];
+        self.buffer.cursor_x = unicode::next_boundary(line, self.buffer.cursor_x);
+    }
+
+    /// Moves the cursor back to the start of the grapheme cluster it is in,
+    /// so it never rests on a combining mark or the middle of an emoji.
+    fn snap_cursor(&mut self) {
+        let line = &self.buffer.content[self.buffer.cursor_y];
+        self.buffer.cursor_x = unicode::cluster_start(line, self.buffer.cursor_x);
+    }
+
+    fn move_cursor_up(&mut self) {
+        if self.buffer.cursor_y > 0 {
+            self.buffer.cursor_y = self.visible_start(self.buffer.cursor_y - 1);
+            self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(self.buffer.cursor_y));
+        }
+    }
+
+    fn move_cursor_down(&mut self) {
+        if self.next_visible(self.buffer.cursor_y) < self.buffer.content.len() {
+            self.buffer.cursor_y = self.next_visible(self.buffer.cursor_y);
+            self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(self.buffer.cursor_y));
+        }
+    }
+
+    fn snapshot(&self) -> UndoState {
+        UndoState {
+            content: self.buffer.content.clone(),
+            cursor_x: self.buffer.cursor_x,
+            cursor_y: self.buffer.cursor_y,
+            modified: self.buffer.modified,
+        }
+    }
+
+    /// Marks the buffer as matching its file, and every other state undo
+    /// or redo can reach as differing from it.
+    fn mark_saved(&mut self) {
+        self.buffer.modified = false;
+        for state in self.buffer.undo_stack.iter_mut().chain(&mut self.buffer.redo_stack) {
+            state.modified = true;
+        }
+    }
+
+    fn push_undo(&mut self, state: UndoState) {
+        if self.buffer.undo_stack.len() == UNDO_LIMIT {
+            self.buffer.undo_stack.remove(0);
+        }
+        self.buffer.undo_stack.push(state);
+        self.buffer.redo_stack.clear();
+        self.change_tick += 1;
+    }
+
+    /// Records the buffer before a change. Everything typed in one insert
+    /// session shares a single snapshot so it undoes as one step.
+    fn checkpoint(&mut self) {
+        if self.undo_group_open {
+            return;
+        }
+        self.push_undo(self.snapshot());
+        if let Mode::Insert | Mode::Replace = self.mode {
+            self.undo_group_open = true;
+        }
+    }
+
+    fn undo(&mut self) {
+        match self.buffer.undo_stack.pop() {
+            Some(state) => {
+                let current = self.snapshot();
+                self.buffer.redo_stack.push(current);
+                self.restore(state);
+            }
+            None => self.status_message = Some("Already at oldest change".to_string()),
+        }
+    }
+
+    fn redo(&mut self) {
+        match self.buffer.redo_stack.pop() {
+            Some(state) => {
+                let current = self.snapshot();
+                self.buffer.undo_stack.push(current);
+                self.restore(state);
+            }
+            None => self.status_message = Some("Already at newest change".to_string()),
+        }
+    }
+
+    fn restore(&mut self, state: UndoState) {
+        self.buffer.content = state.content;
+        self.buffer.word_count = None;
+        self.buffer.cursor_y = std::cmp::min(state.cursor_y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(state.cursor_x, self.line_len(self.buffer.cursor_y));
+        self.buffer.modified = state.modified;
+    }
+
+    fn insert_char(&mut self, c: char) {
+        self.checkpoint();
+        let x = byte_offset(&self.buffer.content[self.buffer.cursor_y], self.buffer.cursor_x);
+        self.buffer.content[self.buffer.cursor_y].insert(x, c);
+        self.shift_snippet_stops(self.buffer.cursor_y, self.buffer.cursor_x, 1);
+        self.buffer.cursor_x += 1;
+        self.buffer.modified = true;
+    }
+
+    /// Inserts a typed character. With `autopairs`, openers get their closer
+    /// and typing a closer that is already next just steps over it.
+    fn insert_typed(&mut self, c: char) {
+        if !self.autopairs {
+            self.insert_char(c);
+            return;
+        }
+        let line = &self.buffer.content[self.buffer.cursor_y];
+        let next = line.chars().nth(self.buffer.cursor_x);
+        let prev = self.buffer.cursor_x.checked_sub(1).and_then(|x| line.chars().nth(x));
+        if matches!(c, ')' | ']' | '}' | '"' | '\'') && next == Some(c) {
+            self.buffer.cursor_x += 1;
+            return;
+        }
+        let closer = match c {
+            '(' => Some(')'),
+            '[' => Some(']'),
+            '{' => Some('}'),
+            '"' => Some('"'),
+            // Leave apostrophes in words like "don't" alone
+            '\'' if !prev.is_some_and(|p| p.is_alphanumeric()) => Some('\''),
+            _ => None,
+        };
+        self.insert_char(c);
+        if let Some(closer) = closer {
+            self.insert_char(closer);
+            self.buffer.cursor_x -= 1;
+        }
+    }
+
+    /// Inserts a tab, or with `expandtab` enough spaces to reach the next tab stop.
+    fn insert_tab(&mut self) {
+        if self.expandtab() {
+            let col = self.display_col(self.buffer.cursor_y, self.buffer.cursor_x);
+            for _ in 0..self.tab_width() - col % self.tab_width() {
+                self.insert_char(' ');
+            }
+        } else {
+            self.insert_char('\t');
+        }
+    }
+
+    /// Expands the snippet named by the word before the cursor, if there is
+    /// one for the buffer's file type, and moves to its first tab stop.
+    fn expand_snippet(&mut self) -> bool {
+        let y = self.buffer.cursor_y;
+        let chars: Vec<char> = self.buffer.content[y].chars().collect();
+        let x = std::cmp::min(self.buffer.cursor_x, chars.len());
+        let mut start = x;
+        while start > 0 && is_word_char(chars[start - 1]) {
+            start -= 1;
+        }
+        if start == x {
+            return false;
+        }
+        let trigger: String = chars[start..x].iter().collect();
+        let body = [self.buffer.filetype.as_str(), "all"]
+            .iter()
+            .find_map(|filetype| self.snippets.get(&(filetype.to_string(), trigger.clone())));
+        let Some(body) = body else {
+            return false;
+        };
+        let indent: String = chars.iter().take_while(|c| c.is_whitespace()).collect();
+        let tab = if self.expandtab() { " ".repeat(self.tab_width()) } else { "\t".to_string() };
+        let (mut lines, stops) = snippet::expand(body, &indent, &tab);
+        self.checkpoint();
+        let before: String = chars[..start].iter().collect();
+        let after: String = chars[x..].iter().collect();
+        lines[0].insert_str(0, &before);
+        lines.last_mut().unwrap().push_str(&after);
+        let mut stops: Vec<(usize, usize)> = stops
+            .into_iter()
+            .map(|(line, col)| (y + line, if line == 0 { col + start } else { col }))
+            .collect();
+        let added = lines.len() - 1;
+        let tail_start = lines[added].chars().count() - (chars.len() - x);
+        self.buffer.content.splice(y..=y, lines);
+        self.shift_marks(y + 1, added as isize);
+        // Stops of an outer snippet after the trigger follow the text after
+        // it to the snippet's last line
+        for stop in &mut self.snippet_stops {
+            if stop.0 == y && stop.1 >= x {
+                *stop = (y + added, stop.1 - x + tail_start);
+            } else if stop.0 == y && stop.1 > start {
+                stop.1 = start;
+            }
+        }
+        self.buffer.modified = true;
+        // A snippet expanded inside another is finished before going on
+        stops.append(&mut self.snippet_stops);
+        self.snippet_stops = stops;
+        self.next_snippet_stop();
+        true
+    }
+
+    /// Moves to the next tab stop of the snippets being filled in, if any
+    /// are left.
+    fn next_snippet_stop(&mut self) -> bool {
+        if self.snippet_stops.is_empty() {
+            return false;
+        }
+        let (y, x) = self.snippet_stops.remove(0);
+        self.buffer.cursor_y = std::cmp::min(y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(x, self.line_len(self.buffer.cursor_y));
+        true
+    }
+
+    /// Keeps snippet stops on line `y` with the text they are in after
+    /// `delta` characters were inserted at column `x`, or removed from
+    /// there when negative. Stops in removed text end up at `x`.
+    fn shift_snippet_stops(&mut self, y: usize, x: usize, delta: isize) {
+        for stop in &mut self.snippet_stops {
+            if stop.0 == y && stop.1 >= x {
+                stop.1 = std::cmp::max(stop.1.saturating_add_signed(delta), x);
+            }
+        }
+    }
+
+    fn insert_newline(&mut self) {
+        self.checkpoint();
+        let y = self.buffer.cursor_y;
+        let split = self.buffer.cursor_x;
+        let x = byte_offset(&self.buffer.content[y], split);
+        let current_line = self.buffer.content[y].split_off(x);
+        let indent = self.new_line_indent(y);
+        self.buffer.cursor_x = indent.chars().count();
+        self.buffer.content.insert(y + 1, indent + &current_line);
+        self.shift_marks(y + 1, 1);
+        // Stops after the cursor go down with the rest of the line
+        for stop in &mut self.snippet_stops {
+            if stop.0 == y && stop.1 >= split {
+                *stop = (y + 1, stop.1 - split + self.buffer.cursor_x);
+            }
+        }
+        self.buffer.cursor_y += 1;
+        self.buffer.modified = true;
+    }
+
+    /// Leading whitespace for a line opened below line `y`: a copy of its
+    /// indent, plus one level after an opening `{` in brace languages.
+    /// Empty when `autoindent` is off.
+    fn new_line_indent(&self, y: usize) -> String {
+        if !self.autoindent {
+            return String::new();
+        }
+        let line = &self.buffer.content[y];
+        let mut indent: String = line.chars().take_while(|c| c == &' ' || c == &'\t').collect();
+        if filetype::uses_braces(&self.buffer.filetype) && line.trim_end().ends_with('{') {
+            indent.push_str(&self.indent_unit());
+        }
+        indent
+    }
+
+    /// One level of indentation: a tab, or `tab_width` spaces with `expandtab`.
+    fn indent_unit(&self) -> String {
+        if self.expandtab() {
+            " ".repeat(self.tab_width())
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    /// Deletes up to `n` characters from the cursor to the end of the line,
+    /// as `x` does in vim, and returns them.
+    fn delete_chars(&mut self, n: usize) -> Option<String> {
+        let len = self.line_len(self.buffer.cursor_y);
+        if self.buffer.cursor_x >= len {
+            return None;
+        }
+        self.checkpoint();
+        let line = &mut self.buffer.content[self.buffer.cursor_y];
+        let boundaries = unicode::boundaries(line);
+        let end = boundaries
+            .iter()
+            .filter(|&&boundary| boundary > self.buffer.cursor_x)
+            .nth(n - 1)
+            .map_or(len, |&boundary| boundary);
+        let start = byte_offset(line, self.buffer.cursor_x);
+        let end = byte_offset(line, end);
+        let removed: String = line.drain(start..end).collect();
+        self.buffer.modified = true;
+        let new_len = self.line_len(self.buffer.cursor_y);
+        if self.buffer.cursor_x >= new_len {
+            self.buffer.cursor_x = new_len.saturating_sub(1);
+            self.snap_cursor();
+        }
+        Some(removed)
+    }
+
+    /// Overwrites `n` characters from the cursor with `c`, or replaces them
+    /// with a line break when `c` is `None`. Does nothing if the line has
+    /// fewer than `n` characters left.
+    fn replace_chars(&mut self, c: Option<char>, n: usize) {
+        let x = self.buffer.cursor_x;
+        if x.saturating_add(n) > self.line_len(self.buffer.cursor_y) {
+            return;
+        }
+        let Some(c) = c else {
+            // Split first, then drop the replaced characters from the new line
+            self.insert_newline();
+            let line = &mut self.buffer.content[self.buffer.cursor_y];
+            let start = byte_offset(line, self.buffer.cursor_x);
+            let end = byte_offset(line, self.buffer.cursor_x + n);
+            line.drain(start..end);
+            return;
+        };
+        self.checkpoint();
+        let line = &mut self.buffer.content[self.buffer.cursor_y];
+        let (start, end) = (byte_offset(line, x), byte_offset(line, x + n));
+        line.replace_range(start..end, &c.to_string().repeat(n));
+        self.buffer.cursor_x = x + n - 1;
+        self.buffer.modified = true;
+    }
+
+    /// Adds `delta` to the number under or after the cursor, keeping its
+    /// width when it has leading zeros, and leaves the cursor on its last
+    /// digit. A `-` right before the digits makes the number negative.
+    fn add_to_number(&mut self, delta: i64) {
+        let y = self.buffer.cursor_y;
+        let chars: Vec<char> = self.buffer.content[y].chars().collect();
+        let x = std::cmp::min(self.buffer.cursor_x, chars.len());
+        let Some(mut start) = (x..chars.len()).find(|&i| chars[i].is_ascii_digit()) else {
+            return;
+        };
+        if start == x {
+            while start > 0 && chars[start - 1].is_ascii_digit() {
+                start -= 1;
+            }
+        }
+        let mut end = start;
+        while end < chars.len() && chars[end].is_ascii_digit() {
+            end += 1;
+        }
+        let negative = start > 0 && chars[start - 1] == '-';
+        let digits: String = chars[start..end].iter().collect();
+        let Some(value) = digits
+            .parse::<i64>()
+            .ok()
+            .map(|n| if negative { -n } else { n })
+            .and_then(|n| n.checked_add(delta))
+        else {
+            return;
+        };
+        let width = if digits.len() > 1 && digits.starts_with('0') { digits.len() } else { 0 };
+        let sign = if value < 0 { "-" } else { "" };
+        let number = format!("{}{:0width$}", sign, value.unsigned_abs(), width = width);
+        let start = if negative { start - 1 } else { start };
+        self.checkpoint();
+        let line = &mut self.buffer.content[y];
+        let range = byte_offset(line, start)..byte_offset(line, end);
+        line.replace_range(range, &number);
+        self.buffer.cursor_x = start + number.len() - 1;
+        self.buffer.modified = true;
+    }
+
+    /// The characters of the cursor line that `dw` (or `cw` when `change`)
+    /// acts on, as a `start..end` range. `inner` gives the word under the
+    /// cursor instead, as `iw` does. Word boundaries match `w` and `b`.
+    fn word_range(&self, inner: bool, change: bool, count: usize) -> (usize, usize) {
+        let y = self.buffer.cursor_y;
+        let len = self.line_len(y);
+        let x = std::cmp::min(self.buffer.cursor_x, len);
+        if x == len {
+            return (x, x);
+        }
+        let class = |x| self.char_class(y, x);
+        let run_end = |mut x| {
+            let start = class(x);
+            while x < len && class(x) == start {
+                x += 1;
+            }
+            x
+        };
+        let skip_blanks = |mut x| {
+            while x < len && class(x) == CharClass::Blank {
+                x += 1;
+            }
+            x
+        };
+        if inner {
+            let mut start = x;
+            while start > 0 && class(start - 1) == class(x) {
+                start -= 1;
+            }
+            return (start, run_end(x));
+        }
+        let mut end = x;
+        for i in 0..count {
+            // Like vim, `cw` stops at the end of the word instead of the next one
+            if change {
+                if i > 0 {
+                    end = skip_blanks(end);
+                }
+                end = run_end(end);
+            } else {
+                if class(end) != CharClass::Blank {
+                    end = run_end(end);
+                }
+                end = skip_blanks(end);
+            }
+        }
+        (x, end)
+    }
+
+    /// Deletes a word into the register for `dw` and `diw`, and for `cw` and
+    /// `ciw` (when `change`) starts insert mode in its place.
+    fn change_word(&mut self, change: bool, inner: bool, count: usize) {
+        let (start, end) = self.word_range(inner, change, count);
+        let y = self.buffer.cursor_y;
+        if end > start {
+            let selection =
+                Selection { start: (y, start), end: (y, end - 1), linewise: false, block: false };
+            let text = self.delete_selection(selection);
+            self.set_register(Register { text, linewise: false });
+        }
+        if change {
+            self.buffer.cursor_x = start;
+            self.mode = Mode::Insert;
+            // Text typed next undoes together with the deletion
+            self.undo_group_open = end > start;
+        }
+    }
+
+    /// Wraps each `start..end` range, given as positions with an exclusive
+    /// end, in the pair named by `c`, all in one undo step.
+    fn add_surround(&mut self, ranges: &[(Pos, Pos)], c: char) {
+        let Some((open, close)) = surround_pair(c) else {
+            return;
+        };
+        self.checkpoint();
+        // Ends go in last to first so earlier positions stay valid
+        for &(start, end) in ranges.iter().rev() {
+            self.replace_at(end, 0, close);
+            self.replace_at(start, 0, open);
+        }
+        self.set_cursor(ranges[0].0);
+    }
+
+    /// Removes the pair named by `old` around the cursor, or swaps it for
+    /// the pair named by `new`.
+    fn change_surround(&mut self, old: char, new: Option<char>) {
+        let new_pair = match new {
+            Some(c) => match surround_pair(c) {
+                Some(pair) => Some(pair),
+                None => return,
+            },
+            None => None,
+        };
+        let Some((start, end)) = self.find_surround(old) else {
+            self.status_message = Some(format!("No surrounding {} found", old));
+            return;
+        };
+        self.checkpoint();
+        match new_pair {
+            Some((open, close)) => {
+                self.replace_at(end, 1, close);
+                self.replace_at(start, 1, open);
+            }
+            None => {
+                self.replace_at(end, 1, "");
+                self.replace_at(start, 1, "");
+            }
+        }
+        self.set_cursor(start);
+    }
+
+    /// Replaces `len` characters at `(y, x)` with `text`.
+    fn replace_at(&mut self, (y, x): Pos, len: usize, text: &str) {
+        let line = &mut self.buffer.content[y];
+        let range = byte_offset(line, x)..byte_offset(line, x + len);
+        line.replace_range(range, text);
+        self.buffer.modified = true;
+    }
+
+    /// Positions of the opening and closing characters of the pair named by
+    /// `c` that enclose the cursor. Brackets are matched across lines with
+    /// nesting; quotes pair up left to right within the cursor line.
+    fn find_surround(&self, c: char) -> Option<(Pos, Pos)> {
+        let (open, close) = surround_pair(c)?;
+        let (open, close) = (open.chars().next()?, close.chars().next()?);
+        let (y, x) = (self.buffer.cursor_y, self.buffer.cursor_x);
+        if open == close {
+            let quotes: Vec<usize> = self.buffer.content[y]
+                .chars()
+                .enumerate()
+                .filter_map(|(i, ch)| (ch == open).then_some(i))
+                .collect();
+            return quotes
+                .chunks_exact(2)
+                .find(|pair| pair[0] <= x && x <= pair[1])
+                .map(|pair| ((y, pair[0]), (y, pair[1])));
+        }
+        // Walk back to the first opening bracket not closed before the cursor
+        let mut depth = 0usize;
+        let mut row = y;
+        loop {
+            let chars: Vec<char> = self.buffer.content[row].chars().collect();
+            let from = if row == y { std::cmp::min(x + 1, chars.len()) } else { chars.len() };
+            for col in (0..from).rev() {
+                if chars[col] == close && !(row == y && col == x) {
+                    depth += 1;
+                } else if chars[col] == open {
+                    if depth == 0 {
+                        let end = self.matching_bracket(row, col)?;
+                        return Some(((row, col), end));
+                    }
+                    depth -= 1;
+                }
+            }
+            row = row.checked_sub(1)?;
+        }
+    }
+
+    /// Ends an `I` or `A` block insert by copying the text typed on the first
+    /// line to the same column of the other lines. `I` skips lines too short
+    /// to reach the block; `A` pads them with spaces.
+    fn finish_block_insert(&mut self) {
+        let Some(block) = self.block_insert.take() else {
+            return;
+        };
+        let added = self.line_len(block.first).saturating_sub(block.line_len);
+        if self.buffer.cursor_y != block.first || added == 0 {
+            return;
+        }
+        let line = &self.buffer.content[block.first];
+        let text: String = line.chars().skip(block.x).take(added).collect();
+        if text.is_empty() {
+            return;
+        }
+        for y in block.first + 1..=block.last {
+            let len = self.line_len(y);
+            if len < block.x {
+                if !block.append {
+                    continue;
+                }
+                self.buffer.content[y].push_str(&" ".repeat(block.x - len));
+            }
+            let line = &mut self.buffer.content[y];
+            let at = byte_offset(line, block.x);
+            line.insert_str(at, &text);
+        }
+    }
+
+    /// Swaps upper and lower case for characters `start..end` of line `y`.
+    fn toggle_case(&mut self, y: usize, start: usize, end: usize) {
+        let line = &mut self.buffer.content[y];
+        let (from, to) = (byte_offset(line, start), byte_offset(line, end));
+        let toggled: String = line[from..to]
+            .chars()
+            .map(|c| {
+                if c.is_lowercase() {
+                    c.to_uppercase().collect::<String>()
+                } else {
+                    c.to_lowercase().collect()
+                }
+            })
+            .collect();
+        line.replace_range(from..to, &toggled);
+        self.buffer.modified = true;
+    }
+
+    /// Inserts a line at index `y`, indented like the cursor line when
+    /// `autoindent` is on, and starts insert mode on it.
+    fn open_line(&mut self, y: usize) {
+        self.checkpoint();
+        let indent = if y > self.buffer.cursor_y {
+            self.new_line_indent(self.buffer.cursor_y)
+        } else if self.autoindent {
+            self.buffer.content[y].chars().take_while(|c| c == &' ' || c == &'\t').collect()
+        } else {
+            String::new()
+        };
+        self.buffer.cursor_x = indent.chars().count();
+        self.buffer.content.insert(y, indent);
+        self.shift_marks(y, 1);
+        self.buffer.cursor_y = y;
+        self.buffer.modified = true;
+        self.mode = Mode::Insert;
+        // Text typed on the new line undoes together with opening it
+        self.undo_group_open = true;
+    }
+
+    /// Joins `count` lines (at least two) starting at the cursor line, as `J`
+    /// does in vim. Leading whitespace on each joined line collapses to a
+    /// single space and the cursor lands on the last join point.
+    fn join_lines(&mut self, count: usize) {
+        let joins = std::cmp::min(count.max(2) - 1, self.buffer.content.len() - 1 - self.buffer.cursor_y);
+        if joins == 0 {
+            return;
+        }
+        self.checkpoint();
+        let y = self.buffer.cursor_y;
+        for _ in 0..joins {
+            let next = self.buffer.content.remove(y + 1);
+            self.shift_marks(y + 1, -1);
+            let next = next.trim_start();
+            let line = &mut self.buffer.content[y];
+            let trimmed_len = line.trim_end().len();
+            line.truncate(trimmed_len);
+            self.buffer.cursor_x = line.chars().count();
+            if !line.is_empty() && !next.is_empty() && !next.starts_with(')') {
+                line.push(' ');
+            }
+            line.push_str(next);
+        }
+        self.buffer.modified = true;
+    }
+
+    /// Indents (`>>`) or dedents (`<<`) lines `first..=last` by one level.
+    /// Dedenting only ever removes leading whitespace.
+    fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        let last = std::cmp::min(last, self.buffer.content.len() - 1);
+        self.checkpoint();
+        let (unit, tab_width) = (self.indent_unit(), self.tab_width());
+        for y in first..=last {
+            let line = &mut self.buffer.content[y];
+            if right {
+                if !line.is_empty() {
+                    line.insert_str(0, &unit);
+                }
+                continue;
+            }
+            // Remove one tab, or up to `tab_width` spaces
+            let strip = match line.chars().next() {
+                Some('\t') => 1,
+                _ => line.chars().take(tab_width).take_while(|&c| c == ' ').count(),
+            };
+            line.drain(..strip);
+        }
+        self.buffer.modified = true;
+        self.buffer.cursor_y = first;
+        self.buffer.cursor_x = self.first_non_blank(first);
+    }
+
+    /// Comments out lines `first..=last`, or uncomments them if every
+    /// non-blank line is already commented. The marker goes after the indent.
+    fn toggle_comments(&mut self, first: usize, last: usize) {
+        let last = std::cmp::min(last, self.buffer.content.len() - 1);
+        let prefix = filetype::comment_prefix(&self.buffer.filetype);
+        let uncomment = self
+            .buffer
+            .content
+            .range(first..=last)
+            .filter(|line| !line.trim().is_empty())
+            .all(|line| line.trim_start().starts_with(prefix));
+        self.checkpoint();
+        for y in first..=last {
+            let line = &mut self.buffer.content[y];
+            if line.trim().is_empty() {
+                continue;
+            }
+            let indent = line.len() - line.trim_start().len();
+            if uncomment {
+                let rest = &line[indent + prefix.len()..];
+                let rest = rest.strip_prefix(' ').unwrap_or(rest).to_string();
+                line.truncate(indent);
+                line.push_str(&rest);
+            } else {
+                line.insert_str(indent, &format!("{} ", prefix));
+            }
+        }
+        self.buffer.modified = true;
+        self.buffer.cursor_y = first;
+        self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(first));
+    }
+
+    /// Moves marks and folds after `delta` lines were inserted at line `y`,
+    /// or removed from it when negative. Marks on removed lines are dropped,
+    /// and folds shrink to the lines they have left.
+    fn shift_marks(&mut self, y: usize, delta: isize) {
+        let removed = y..y + delta.unsigned_abs();
+        if delta < 0 {
+            self.buffer.marks.retain(|_, mark| !removed.contains(&mark.0));
+        }
+        for mark in self.buffer.marks.values_mut() {
+            if mark.0 >= y {
+                mark.0 = mark.0.saturating_add_signed(delta);
+            }
+        }
+        if delta < 0 {
+            self.snippet_stops.retain(|stop| !removed.contains(&stop.0));
+        }
+        for stop in &mut self.snippet_stops {
+            if stop.0 >= y {
+                stop.0 = stop.0.saturating_add_signed(delta);
+            }
+        }
+        for jump in &mut self.jumps {
+            if jump.0 == self.current && jump.1 >= y {
+                // Jumps from removed lines go to where they were
+                jump.1 = std::cmp::max(jump.1.saturating_add_signed(delta), y);
+            }
+        }
+        if delta > 0 {
+            // Lines inserted inside a fold join it
+            for fold in &mut self.buffer.folds {
+                if fold.start >= y {
+                    fold.start += removed.len();
+                }
+                if fold.end >= y {
+                    fold.end += removed.len();
+                }
+            }
+            return;
+        }
+        self.buffer.folds.retain_mut(|fold| {
+            let end = match fold.end {
+                end if end >= removed.end => end - removed.len(),
+                end if end >= y => match y.checked_sub(1) {
+                    Some(end) => end,
+                    None => return false,
+                },
+                end => end,
+            };
+            let start = match fold.start {
+                start if start >= removed.end => start - removed.len(),
+                start if start >= y => y,
+                start => start,
+            };
+            fold.start = start;
+            fold.end = end;
+            start <= end
+        });
+    }
+
+    /// The outermost closed fold containing line `y`, as its first and last
+    /// lines.
+    fn closed_fold(&self, y: usize) -> Option<(usize, usize)> {
+        self.buffer
+            .folds
+            .iter()
+            .filter(|fold| fold.closed && fold.start <= y && y <= fold.end)
+            .filter(|fold| fold.end < self.buffer.content.len())
+            .map(|fold| (fold.start, fold.end))
+            .min_by_key(|&(start, end)| (start, std::cmp::Reverse(end)))
+    }
+
+    /// The line on screen for line `y`: the first line of the closed fold
+    /// hiding it, or `y` itself.
+    fn visible_start(&self, y: usize) -> usize {
+        self.closed_fold(y).map_or(y, |(start, _)| start)
+    }
+
+    /// The first line shown on the screen row after the one showing `y`.
+    fn next_visible(&self, y: usize) -> usize {
+        self.closed_fold(y).map_or(y, |(_, end)| end) + 1
+    }
+
+    /// Row of the window, counted from the top, showing line `y`.
+    fn screen_row(&self, y: usize) -> usize {
+        let target = self.visible_start(y);
+        let mut line = self.buffer.row_offset;
+        let mut row = 0;
+        while line < target {
+            line = self.next_visible(line);
+            row += 1;
+        }
+        row
+    }
+
+    /// Opens (`Some(false)`), closes (`Some(true)`) or removes (`None`) the
+    /// fold spanning exactly `start..=end`.
+    fn set_fold(&mut self, (start, end): (usize, usize), closed: Option<bool>) {
+        let index = self.buffer.folds.iter().position(|fold| fold.start == start && fold.end == end);
+        match (index, closed) {
+            (Some(index), Some(closed)) => self.buffer.folds[index].closed = closed,
+            (Some(index), None) => {
+                self.buffer.folds.remove(index);
+            }
+            (None, _) => {}
+        }
+    }
+
+    /// `zo`, `zc`, `za` and `zd` on the fold at the cursor. Opening and
+    /// removing act on the closed fold shown, closing on the innermost open
+    /// fold around the cursor.
+    fn fold_command(&mut self, command: char) {
+        let y = self.buffer.cursor_y;
+        let shown = self.closed_fold(y);
+        let innermost_open = self
+            .buffer
+            .folds
+            .iter()
+            .filter(|fold| !fold.closed && fold.start <= y && y <= fold.end)
+            .min_by_key(|fold| fold.end - fold.start)
+            .map(|fold| (fold.start, fold.end));
+        let (fold, closed) = match (command, shown, innermost_open) {
+            ('o', Some(fold), _) | ('a', Some(fold), _) => (fold, Some(false)),
+            ('c', _, Some(fold)) | ('a', None, Some(fold)) => (fold, Some(true)),
+            ('d', Some(fold), _) | ('d', None, Some(fold)) => (fold, None),
+            _ => {
+                self.status_message = Some("No fold found".to_string());
+                return;
+            }
+        };
+        self.set_fold(fold, closed);
+    }
+
+    /// Draws the line standing for closed fold `start..=end`: its size and
+    /// first line, filled out with dashes.
+    fn draw_fold(&self, stdout: &mut Stdout, (start, end): (usize, usize)) -> crossterm::Result<()> {
+        let width = self.text_cols();
+        let text = self.buffer.content[start].trim().replace('\t', " ");
+        let summary = format!("+--{:>3} lines: {} ", end - start + 1, text);
+        let summary: String = summary.chars().take(width).collect();
+        let fill = "-".repeat(width.saturating_sub(summary.chars().count()));
+        queue!(
+            stdout,
+            style::SetColors(Colors::new(self.theme.fold_fg, self.theme.fold_bg)),
+            style::Print(summary),
+            style::Print(fill),
+            style::SetColors(Colors::new(Color::Reset, Color::Reset)),
+        )
+    }
+
+    /// Moves to the `count`th `target` on the cursor line: onto it for `f`
+    /// and `F`, next to it for `t` and `T`, backward for the uppercase ones.
+    /// A `repeat` of `t` skips a match right next to the cursor so `;` moves
+    /// on. The cursor stays put if there are too few matches.
+    fn find_in_line(&mut self, kind: char, target: char, count: usize, repeat: bool) {
+        let chars: Vec<char> = self.buffer.content[self.buffer.cursor_y].chars().collect();
+        let x = self.buffer.cursor_x;
+        let till = kind == 't' || kind == 'T';
+        let skip = usize::from(till && repeat);
+        let found = if kind.is_lowercase() {
+            (x + 1 + skip..chars.len()).filter(|&i| chars[i] == target).nth(count - 1)
+        } else {
+            (0..x.saturating_sub(skip)).rev().filter(|&i| chars[i] == target).nth(count - 1)
+        };
+        if let Some(i) = found {
+            self.buffer.cursor_x = match (till, kind.is_lowercase()) {
+                (false, _) => i,
+                (true, true) => i - 1,
+                (true, false) => i + 1,
+            };
+        }
+    }
+
+    /// Reports the number of lines, words and characters in the selection,
+    /// or in the whole buffer outside visual mode. Words are separated by
+    /// whitespace, as `wc` counts them.
+    fn word_count(&mut self) {
+        let (scope, lines, words, chars) = match self.selection() {
+            Some(selection) => {
+                let lines = self.selection_text(selection);
+                let (words, chars) = count_words(&lines);
+                ("Selected", lines.len(), words, chars)
+            }
+            None => {
+                // An open insert session changes the text without a new tick
+                let (words, chars) = match self.buffer.word_count {
+                    Some((tick, words, chars)) if tick == self.change_tick && !self.undo_group_open => {
+                        (words, chars)
+                    }
+                    _ => {
+                        let (words, chars) = count_words(&self.buffer.content);
+                        self.buffer.word_count = Some((self.change_tick, words, chars));
+                        (words, chars)
+                    }
+                };
+                ("Buffer", self.buffer.content.len(), words, chars)
+            }
+        };
+        self.status_message = Some(format!(
+            "{}: {} lines, {} words, {} characters",
+            scope, lines, words, chars
+        ));
+    }
+
+    /// Jumps to the mark `name`, to its exact column or, when `linewise`, to
+    /// the first non-blank of its line.
+    fn jump_to_mark(&mut self, name: char, linewise: bool) {
+        let Some(&(y, x)) = self.buffer.marks.get(&name) else {
+            self.status_message = Some(format!("Mark {} not set", name));
+            return;
+        };
+        self.push_jump();
+        let y = std::cmp::min(y, self.buffer.content.len() - 1);
+        self.buffer.cursor_y = y;
+        self.buffer.cursor_x = if linewise {
+            self.first_non_blank(y)
+        } else {
+            std::cmp::min(x, self.line_len(y))
+        };
+    }
+
+    /// Deletes `n` lines starting at the cursor line and returns them.
+    fn delete_lines(&mut self, n: usize) -> Vec<String> {
+        self.checkpoint();
+        let end = std::cmp::min(self.buffer.cursor_y.saturating_add(n), self.buffer.content.len());
+        let removed = self.buffer.content.drain(self.buffer.cursor_y..end).collect();
+        self.shift_marks(self.buffer.cursor_y, -((end - self.buffer.cursor_y) as isize));
+        if self.buffer.content.is_empty() {
+            self.buffer.content.push(String::new());
+        }
+        self.buffer.cursor_y = std::cmp::min(self.buffer.cursor_y, self.buffer.content.len() - 1);
+        self.buffer.cursor_x = std::cmp::min(self.buffer.cursor_x, self.line_len(self.buffer.cursor_y));
+        self.buffer.modified = true;
+        removed
+    }
+
+    /// The selected text, one entry per line.
+    fn selection_text(&self, selection: Selection) -> Vec<String> {
+        (selection.start.0..=selection.end.0)
+            .map(|y| {
+                let line = &self.buffer.content[y];
+                let (start, end) = selection.columns(y, line.chars().count()).unwrap();
+                line.chars().skip(start).take(end - start).collect()
+            })
+            .collect()
+    }
+
+    /// Whether a yank or paste selected via `register` goes through the
+    /// system clipboard.
+    fn use_clipboard(&self, register: Option<char>) -> bool {
+        match register {
+            Some('+') | Some('*') => true,
+            Some(_) => false,
+            None => self.clipboard,
+        }
+    }
+
+    /// Stores yanked or deleted text in the unnamed register and the one
+    /// picked with `"x`. An uppercase name appends to its lowercase register,
+    /// and `+` or the `clipboard` option copy to the system clipboard.
+    fn set_register(&mut self, register: Register) {
+        let name = self.selected_register.take();
+        if self.use_clipboard(name) {
+            let mut text = register.text.join("\n");
+            if register.linewise {
+                text.push('\n');
+            }
+            if !clipboard::set(&text) {
+                self.status_message =
+                    Some("Clipboard unavailable, using the internal register".to_string());
+            }
+        }
+        let register = match name {
+            Some(c) if c.is_ascii_uppercase() => {
+                let named = self.registers.entry(c.to_ascii_lowercase()).or_default();
+                if named.linewise || register.linewise || named.text.is_empty() {
+                    named.text.extend(register.text);
+                    named.linewise |= register.linewise;
+                } else {
+                    // Characterwise text continues the last line
+                    let mut lines = register.text.into_iter();
+                    if let Some(first) = lines.next() {
+                        named.text.last_mut().unwrap().push_str(&first);
+                    }
+                    named.text.extend(lines);
+                }
+                named.clone()
+            }
+            Some(c) if c.is_ascii_alphanumeric() => {
+                self.registers.insert(c, register.clone());
+                register
+            }
+            _ => register,
+        };
+        self.registers.insert('"', register);
+    }
+
+    /// The text to paste, read from the system clipboard when selected and
+    /// available, otherwise from the picked or unnamed register.
+    fn get_register(&mut self) -> Register {
+        let name = self.selected_register.take();
+        if self.use_clipboard(name) {
+            match clipboard::get() {
+                Some(text) => {
+                    let linewise = text.ends_with('\n');
+                    let text = text.strip_suffix('\n').unwrap_or(&text);
+                    return Register {
+                        text: text.split('\n').map(String::from).collect(),
+                        linewise,
+                    };
+                }
+                None => {
+                    self.status_message =
+                        Some("Clipboard unavailable, using the internal register".to_string());
+                }
+            }
+        }
+        let name = match name {
+            Some(c) if c.is_ascii_alphanumeric() => c.to_ascii_lowercase(),
+            _ => '"',
+        };
+        self.registers.get(&name).cloned().unwrap_or_default()
+    }
+
+    /// Pastes the register `count` times after (`p`) or before (`P`) the cursor.
+    fn paste(&mut self, after: bool, count: usize) {
+        let register = self.get_register();
+        if register.text.is_empty() {
+            return;
+        }
+        self.checkpoint();
+        self.buffer.modified = true;
+        let mut text = register.text.clone();
+        for _ in 1..count {
+            if register.linewise {
+                text.extend(register.text.iter().cloned());
+            } else {
+                // Each copy continues on the line where the previous one ended
+                let last = text.pop().unwrap();
+                text.push(last + &register.text[0]);
+                text.extend(register.text[1..].iter().cloned());
+            }
+        }
+        if register.linewise {
+            let y = if after { self.buffer.cursor_y + 1 } else { self.buffer.cursor_y };
+            let added = text.len() as isize;
+            self.buffer.content.splice(y..y, text);
+            self.shift_marks(y, added);
+            self.buffer.cursor_y = y;
+            self.buffer.cursor_x = self.first_non_blank(y);
+        } else {
+            let len = self.line_len(self.buffer.cursor_y);
+            let x = if after && len > 0 {
+                std::cmp::min(self.buffer.cursor_x + 1, len)
+            } else {
+                std::cmp::min(self.buffer.cursor_x, len)
+            };
+            self.insert_text(self.buffer.cursor_y, x, &text);
+            self.buffer.cursor_x = x;
+        }
+    }
+
+    /// Inserts characterwise `text` at `(y, x)`; each entry after the first
+    /// starts a new line.
+    fn insert_text(&mut self, y: usize, x: usize, text: &[String]) {
+        let at = byte_offset(&self.buffer.content[y], x);
+        let tail = self.buffer.content[y].split_off(at);
+        self.buffer.content[y].push_str(&text[0]);
+        let rest = &text[1..];
+        self.buffer.content.splice(y + 1..y + 1, rest.iter().cloned());
+        self.shift_marks(y + 1, rest.len() as isize);
+        self.buffer.content[y + rest.len()].push_str(&tail);
+    }
+
+    /// Removes the selected text, or whole lines for a linewise selection,
+    /// and returns what was removed.
+    fn delete_selection(&mut self, selection: Selection) -> Vec<String> {
+        self.checkpoint();
+        self.buffer.modified = true;
+        let (first, last) = (selection.start.0, selection.end.0);
+        if selection.linewise {
+            let removed: Vec<String> = self.buffer.content.drain(first..=last).collect();
+            self.shift_marks(first, -(removed.len() as isize));
+            if self.buffer.content.is_empty() {
+                self.buffer.content.push(String::new());
+            }
+            self.buffer.cursor_y = std::cmp::min(first, self.buffer.content.len() - 1);
+            self.buffer.cursor_x = self.first_non_blank(self.buffer.cursor_y);
+            return removed;
+        }
+
+        if selection.block {
+            let mut removed = Vec::new();
+            for y in first..=last {
+                let line = &mut self.buffer.content[y];
+                let (start, end) = selection.columns(y, line.chars().count()).unwrap();
+                let (start, end) = (byte_offset(line, start), byte_offset(line, end));
+                removed.push(line.drain(start..end).collect());
+            }
+            self.buffer.cursor_y = first;
+            self.buffer.cursor_x =
+                std::cmp::min(selection.start.1, self.line_len(first).saturating_sub(1));
+            return removed;
+        }
+
+        let start = byte_offset(&self.buffer.content[first], selection.start.1);
+        let end_line = &self.buffer.content[last];
+        let end = byte_offset(end_line, selection.end.1 + 1);
+        let tail = end_line[end..].to_string();
+        let mut removed: Vec<String> = Vec::new();
+        if first == last {
+            removed.push(self.buffer.content[first][start..end].to_string());
+        } else {
+            removed.push(self.buffer.content[first][start..].to_string());
+            removed.extend(self.buffer.content.drain(first + 1..last));
+            let end_line = self.buffer.content.remove(first + 1);
+            self.shift_marks(first + 1, -((last - first) as isize));
+            removed.push(end_line[..end].to_string());
+        }
+        self.buffer.content[first].truncate(start);
+        self.buffer.content[first].push_str(&tail);
+        self.buffer.cursor_y = first;
+        self.buffer.cursor_x = std::cmp::min(selection.start.1, self.line_len(first).saturating_sub(1));
+        removed
+    }
+
+    fn handle_backspace(&mut self) {
+        if self.buffer.cursor_x > 0 || self.buffer.cursor_y > 0 {
+            self.checkpoint();
+        }
+        if self.autopairs && self.buffer.cursor_x > 0 {
+            // Backspacing inside an empty pair removes both halves
+            let mut chars = self.buffer.content[self.buffer.cursor_y].chars().skip(self.buffer.cursor_x - 1);
+            if let (Some(open), Some(close)) = (chars.next(), chars.next()) {
+                let pairs = [('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+                if pairs.contains(&(open, close)) {
+                    let x = byte_offset(&self.buffer.content[self.buffer.cursor_y], self.buffer.cursor_x);
+                    self.buffer.content[self.buffer.cursor_y].remove(x);
+                    self.shift_snippet_stops(self.buffer.cursor_y, self.buffer.cursor_x, -1);
+                }
+            }
+        }
+        if self.buffer.cursor_x > 0 {
+            let line = &mut self.buffer.content[self.buffer.cursor_y];
+            let start = unicode::prev_boundary(line, self.buffer.cursor_x);
+            let (from, to) = (byte_offset(line, start), byte_offset(line, self.buffer.cursor_x));
+            line.drain(from..to);
+            let removed = self.buffer.cursor_x - start;
+            self.shift_snippet_stops(self.buffer.cursor_y, start, -(removed as isize));
+            self.buffer.cursor_x = start;
+            self.buffer.modified = true;
+        } else if self.buffer.cursor_y > 0 {
+            let y = self.buffer.cursor_y;
+            let previous_len = self.line_len(y - 1);
+            // Stops on the joined line go up with it
+            for stop in &mut self.snippet_stops {
+                if stop.0 == y {
+                    *stop = (y - 1, stop.1 + previous_len);
+                }
+            }
+            let current_line = self.buffer.content.remove(y);
+            self.shift_marks(y, -1);
+            self.buffer.cursor_y -= 1;
+            self.buffer.content[self.buffer.cursor_y].push_str(&current_line);
+            self.buffer.cursor_x = previous_len;
+            self.buffer.modified = true;
+        }
+    }
+}
+
+/// What each key after a built-in prefix such as `d` or `z` does, for the
+/// popup shown when a sequence pauses.
+fn pending_hints(prefix: &str) -> &'static [(&'static str, &'static str)] {
+    match prefix {
+        "d" => &[
+            ("d", "delete line"),
+            ("w", "delete word"),
+            ("iw", "delete inner word"),
+            ("s", "delete surrounding"),
+        ],
+        "c" => &[("w", "change word"), ("iw", "change inner word"), ("s", "change surrounding")],
+        "di" | "ci" => &[("w", "inner word")],
+        "y" => &[("y", "yank line"), ("s", "add surrounding")],
+        "ys" => &[("iw", "around inner word"), ("w", "around word"), ("s", "around line")],
+        ">" => &[(">", "indent line")],
+        "<" => &[("<", "dedent line")],
+        "g" => &[("g", "first line"), ("c", "comment"), ("<C-g>", "word count")],
+        "gc" => &[("c", "comment line")],
+        "z" => &[
+            ("f", "create fold"),
+            ("o", "open fold"),
+            ("c", "close fold"),
+            ("a", "toggle fold"),
+            ("d", "delete fold"),
+            ("R", "open all folds"),
+            ("M", "close all folds"),
+            ("E", "delete all folds"),
+            ("t", "cursor line to top"),
+            ("z", "cursor line to center"),
+            ("b", "cursor line to bottom"),
+        ],
+        "<C-w>" => &[
+            ("w", "next window"),
+            ("W", "previous window"),
+            ("s", "split"),
+            ("v", "split vertically"),
+            ("c", "close window"),
+            ("o", "only this window"),
+        ],
+        "q" => &[("a-z", "record macro")],
+        "@" => &[("a-z", "play macro"), ("@", "play last macro")],
+        "m" => &[("a-z", "set mark")],
+        "`" => &[("a-z", "go to mark")],
+        "'" => &[("a-z", "go to mark's line")],
+        "\"" => &[("a-z", "use register")],
+        "r" => &[("char", "replace character")],
+        "f" | "t" | "F" | "T" => &[("char", "find in line")],
+        _ => &[],
+    }
+}
+
+/// The first decimal number in `line`, with a `-` right before it taken as
+/// its sign, for `:sort n`.
+fn first_number(line: &str) -> Option<i64> {
+    let start = line.find(|c: char| c.is_ascii_digit())?;
+    let end = line[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(line.len(), |len| start + len);
+    let value = line[start..end].parse::<i64>().unwrap_or(i64::MAX);
+    Some(if line[..start].ends_with('-') { -value } else { value })
+}
+
+/// The words and characters in `lines`, with words separated by whitespace.
+fn count_words<'a>(lines: impl IntoIterator<Item = &'a String>) -> (usize, usize) {
+    lines.into_iter().fold((0, 0), |(words, chars), line| {
+        (words + line.split_whitespace().count(), chars + line.chars().count())
+    })
+}
+
+/// Reads the file at `path`, and whether it was cut short: one larger than
+/// `LARGE_FILE_BYTES` is only read up to the last line break before that.
+fn read_text(path: &Path) -> io::Result<(String, bool)> {
+    let mut file = fs::File::open(path)?;
+    let mut bytes = Vec::new();
+    let partial = file.metadata()?.len() > LARGE_FILE_BYTES;
+    if partial {
+        file.take(LARGE_FILE_BYTES).read_to_end(&mut bytes)?;
+        let end = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        bytes.truncate(end);
+    } else {
+        file.read_to_end(&mut bytes)?;
+    }
+    let text = String::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+    Ok((text, partial))
+}
+
+/// Modification time and size of the file at `path`, to notice changes.
+fn disk_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// The editor's directory under `$XDG_CACHE_HOME`, or `~/.cache` without it.
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("text_editor"))
+}
+
+/// Splits file text into buffer lines, dropping `\n` or `\r\n` endings. There
+/// is always at least one line.
+fn split_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+/// Runs `command` with `sh -c`, feeding it `input` on stdin, and collects
+/// what it prints.
+fn run_shell(command: &str, input: Option<&str>) -> io::Result<Output> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Feeding stdin from another thread while stdout is read keeps both
+    // pipes draining, so a command with a lot of output cannot deadlock
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_string();
+            Some(thread::spawn(move || stdin.write_all(input.as_bytes())))
+        }
+        _ => None,
+    };
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        // A command that stops reading early closes the pipe; not an error
+        let _ = writer.join();
+    }
+    Ok(output)
+}
+
+/// What to report for a shell command that failed: its exit status and the
+/// first line of its error output. `None` if it succeeded.
+fn shell_failure(output: &Output) -> Option<String> {
+    if output.status.success() {
+        return None;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Some(match (output.status.code(), stderr.lines().next()) {
+        (Some(code), Some(line)) => format!("Shell returned {}: {}", code, line),
+        (Some(code), None) => format!("Shell returned {}", code),
+        (None, _) => "Shell command killed by a signal".to_string(),
+    })
+}
+
+/// The opening and closing text of the pair named by `c`, which can be
+/// either of its characters.
+fn surround_pair(c: char) -> Option<(&'static str, &'static str)> {
+    match c {
+        '(' | ')' => Some(("(", ")")),
+        '[' | ']' => Some(("[", "]")),
+        '{' | '}' => Some(("{", "}")),
+        '"' => Some(("\"", "\"")),
+        '\'' => Some(("'", "'")),
+        _ => None,
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The find command searching the other way, for `,`.
+fn reverse_find(kind: char) -> char {
+    match kind {
+        'f' => 'F',
+        'F' => 'f',
+        't' => 'T',
+        _ => 't',
+    }
+}
+
+/// Splits a substitute spec like `/foo/bar/g` into pattern, replacement and
+/// flags. The first character is the delimiter; it can be escaped with `\`.
+fn parse_substitute(spec: &str) -> Option<(String, String, String)> {
+    let mut chars = spec.chars();
+    let delimiter = chars.next()?;
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    for c in chars {
+        let in_flags = parts.len() == 3;
+        let part = parts.last_mut().unwrap();
+        if escaped {
+            if c != delimiter {
+                part.push('\\');
+            }
+            part.push(c);
+            escaped = false;
+        } else if c == '\\' && !in_flags {
+            escaped = true;
+        } else if c == delimiter && !in_flags {
+            parts.push(String::new());
+        } else {
+            part.push(c);
+        }
+    }
+    if parts.len() < 2 {
+        return None;
+    }
+    let flags = if parts.len() > 2 { parts.pop().unwrap() } else { String::new() };
+    let replacement = parts.pop().unwrap();
+    let pattern = parts.pop().unwrap();
+    Some((pattern, replacement, flags))
+}
+
+/// Character ranges of the non-overlapping, non-empty matches of `regex`
+/// in `line`.
+fn match_ranges(line: &str, regex: &Regex) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut from = 0;
+    while let Some((start, end)) = regex.find_at(line, from) {
+        if end > start {
+            let first = line[..start].chars().count();
+            ranges.push((first, first + line[start..end].chars().count()));
+        }
+        match line[end..].chars().next() {
+            Some(c) if start == end => from = end + c.len_utf8(),
+            Some(_) => from = end,
+            None => break,
+        }
+    }
+    ranges
+}
+
+/// Converts a character index on `line` into the byte offset `String` methods expect.
+fn byte_offset(line: &str, char_idx: usize) -> usize {
+    line.char_indices()
+        .nth(char_idx)
+        .map_or(line.len(), |(i, _)| i)
+}
+
+fn main() -> crossterm::Result<()> {
+    let (config, config_errors) = Config::load();
+    let mut editor = Editor::new(config);
+    if let Some(e) = config_errors {
+        editor.status_message = Some(format!("Config errors, those settings are ignored: {}", e));
+    }
+    let mut session = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--session" {
+            session = Some(args.next().unwrap_or_else(|| session::DEFAULT_FILE.to_string()));
+            continue;
+        }
+        if let Err(e) = editor.edit(PathBuf::from(&arg)) {
+            editor.status_message = Some(format!("Error opening {}: {}", arg, e));
+        }
+    }
+    editor.switch_buffer(0);
+    // The session picks its own active buffer and windows
+    if let Some(path) = session {
+        editor.load_session(Path::new(&path));
+    }
+    editor.run()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(lines: &[&str]) -> Editor {
+        let mut editor = Editor::new(Config::default());
+        editor.buffer.content = lines.iter().map(|line| line.to_string()).collect();
+        editor
+    }
+
+    /// A fresh file in the temporary directory holding `text`. The cache
+    /// goes there too, so tests leave the user's alone.
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("text_editor-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("XDG_CACHE_HOME", dir.join("cache"));
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode, modifiers: KeyModifiers) {
+        editor.handle_keypress(KeyEvent::new(code, modifiers)).unwrap();
+    }
+
+    /// Types `keys` as plain key presses, with `\x1b` for Esc, `\x08` for
+    /// Backspace, `\t` for Tab and `\n` for Enter.
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\x1b' => KeyCode::Esc,
+                '\x08' => KeyCode::Backspace,
+                '\n' => KeyCode::Enter,
+                '\t' => KeyCode::Tab,
+                c => KeyCode::Char(c),
+            };
+            press(editor, code, KeyModifiers::NONE);
+        }
+    }
+
+    #[test]
+    fn inserts_multibyte_characters_mid_line() {
+        let mut editor = editor_with(&["abcd"]);
+        type_keys(&mut editor, "lli");
+        type_keys(&mut editor, "é");
+        assert_eq!(editor.buffer.content[0], "abécd");
+        assert_eq!(editor.buffer.cursor_x, 3);
+        type_keys(&mut editor, "€");
+        assert_eq!(editor.buffer.content[0], "abé€cd");
+        assert_eq!(editor.buffer.cursor_x, 4);
+    }
+
+    #[test]
+    fn backspaces_over_multibyte_characters_mid_line() {
+        let mut editor = editor_with(&["aé€b"]);
+        type_keys(&mut editor, "llli");
+        assert_eq!(editor.buffer.cursor_x, 3);
+        type_keys(&mut editor, "\x08");
+        assert_eq!(editor.buffer.content[0], "aéb");
+        assert_eq!(editor.buffer.cursor_x, 2);
+        type_keys(&mut editor, "\x08");
+        assert_eq!(editor.buffer.content[0], "ab");
+        assert_eq!(editor.buffer.cursor_x, 1);
+    }
+
+    #[test]
+    fn deletes_multibyte_characters_under_the_cursor() {
+        let mut editor = editor_with(&["a€éb"]);
+        type_keys(&mut editor, "lx");
+        assert_eq!(editor.buffer.content[0], "aéb");
+        assert_eq!(editor.buffer.cursor_x, 1);
+    }
+
+    #[test]
+    fn the_cursor_column_counts_clusters_as_drawn() {
+        let mut editor = editor_with(&["\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{2764}\u{FE0F}x"]);
+        type_keys(&mut editor, "l");
+        assert_eq!(editor.display_col(0, editor.buffer.cursor_x), 2);
+        type_keys(&mut editor, "l");
+        assert_eq!(editor.display_col(0, editor.buffer.cursor_x), 4);
+        assert_eq!(editor.col_to_char(0, 3), 5);
+    }
+
+    #[test]
+    fn undo_reverts_an_insert_session_in_one_step() {
+        let mut editor = editor_with(&["abc"]);
+        type_keys(&mut editor, "liXY\x1b");
+        assert_eq!(editor.buffer.content.to_vec(), ["aXYbc"]);
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buffer.content.to_vec(), ["abc"]);
+        assert_eq!((editor.buffer.cursor_y, editor.buffer.cursor_x), (0, 1));
+    }
+
+    #[test]
+    fn undo_restores_a_deleted_line() {
+        let mut editor = editor_with(&["one", "two", "three"]);
+        type_keys(&mut editor, "jdd");
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "three"]);
+        assert_eq!((editor.buffer.cursor_y, editor.buffer.cursor_x), (1, 0));
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "two", "three"]);
+        assert_eq!((editor.buffer.cursor_y, editor.buffer.cursor_x), (1, 0));
+    }
+
+    #[test]
+    fn redo_reapplies_what_undo_reverted() {
+        let mut editor = editor_with(&["one", "two", "three"]);
+        type_keys(&mut editor, "jddx");
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "hree"]);
+        type_keys(&mut editor, "uu");
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "two", "three"]);
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "three"]);
+        assert_eq!((editor.buffer.cursor_y, editor.buffer.cursor_x), (1, 0));
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "hree"]);
+        // Nothing left to redo
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.content.to_vec(), ["one", "hree"]);
+    }
+
+    #[test]
+    fn a_new_change_after_undo_drops_the_redo_history() {
+        let mut editor = editor_with(&["abc"]);
+        type_keys(&mut editor, "xu");
+        type_keys(&mut editor, "$x");
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert_eq!(editor.buffer.content.to_vec(), ["ab"]);
+    }
+
+    #[test]
+    fn undo_reverts_a_global_command_in_one_step() {
+        let mut editor = editor_with(&["foo 1", "bar", "foo 2"]);
+        type_keys(&mut editor, ":g/foo/d\n");
+        assert_eq!(editor.buffer.content.to_vec(), ["bar"]);
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buffer.content.to_vec(), ["foo 1", "bar", "foo 2"]);
+    }
+
+    #[test]
+    fn saving_keeps_a_trailing_blank_line() {
+        for text in ["a\n\n", "a\n", "", "\n\n"] {
+            let path = temp_file("blank_line.txt", text);
+            let mut editor = Editor::new(Config::default());
+            editor.edit(path.clone()).unwrap();
+            editor.save_file().unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn a_second_editor_on_a_file_keeps_its_own_swap_file() {
+        let path = temp_file("shared.txt", "abc\n");
+        let mut first = Editor::new(Config::default());
+        first.edit(path.clone()).unwrap();
+        type_keys(&mut first, "x");
+        first.write_swaps();
+        let usual = swapfile::swap_path(&path).unwrap();
+        assert_eq!(first.buffer.swap_file.as_ref(), Some(&usual));
+
+        let mut second = Editor::new(Config::default());
+        second.edit(path.clone()).unwrap();
+        type_keys(&mut second, "dd");
+        second.write_swaps();
+        let other = second.buffer.swap_file.clone().unwrap();
+        assert_ne!(other, usual);
+        assert!(second.status_message.as_ref().unwrap().starts_with("Another swap file exists"));
+        assert_eq!(swapfile::read(&path).unwrap(), ["bc"]);
+
+        second.remove_swaps();
+        first.remove_swaps();
+        assert!(!usual.exists() && !other.exists());
+    }
+
+    #[test]
+    fn undoing_back_to_the_saved_text_is_unmodified() {
+        let path = temp_file("undo_saved.txt", "abc\n");
+        let mut editor = Editor::new(Config::default());
+        editor.edit(path).unwrap();
+        type_keys(&mut editor, "x");
+        assert!(editor.buffer.modified);
+        type_keys(&mut editor, "u");
+        assert!(!editor.buffer.modified);
+        press(&mut editor, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        type_keys(&mut editor, ":w\nx");
+        assert!(editor.buffer.modified);
+        type_keys(&mut editor, "u");
+        assert!(!editor.buffer.modified);
+        // Before the save differs from the file now
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buffer.content.to_vec(), ["abc"]);
+        assert!(editor.buffer.modified);
+    }
+
+    #[test]
+    fn undo_brings_back_whitespace_trimmed_on_save() {
+        let path = temp_file("trimmed.txt", "a  \nb\t\n");
+        let mut editor = Editor::new(Config::default());
+        editor.trim_trailing_whitespace = true;
+        editor.edit(path.clone()).unwrap();
+        type_keys(&mut editor, "$:w\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\n");
+        assert_eq!(editor.buffer.cursor_x, 0);
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buffer.content.to_vec(), ["a  ", "b\t"]);
+    }
+
+    #[test]
+    fn writing_to_another_path_writes_a_copy() {
+        let path = temp_file("original.txt", "abc\n");
+        let copy = path.with_file_name("copy.txt");
+        let _ = fs::remove_file(&copy);
+        let mut editor = Editor::new(Config::default());
+        editor.edit(path.clone()).unwrap();
+        type_keys(&mut editor, "x");
+        type_keys(&mut editor, &format!(":w {}\n", copy.display()));
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "bc\n");
+        assert_eq!(editor.buffer.filename.as_ref(), Some(&path));
+        assert!(editor.buffer.modified);
+        // An existing file is only overwritten with !
+        type_keys(&mut editor, &format!(":w {}\n", copy.display()));
+        assert_eq!(editor.status_message.as_deref(), Some("File exists (add ! to override)"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc\n");
+    }
+
+    #[test]
+    fn set_indentation_survives_a_file_type_change_and_reload() {
+        let path = temp_file("indent.rs", "fn main() {}\n");
+        let mut editor = Editor::new(Config::default());
+        editor.edit(path).unwrap();
+        type_keys(&mut editor, ":set ts=3\n:set noet\n:set ft=python\n");
+        assert_eq!((editor.tab_width(), editor.expandtab()), (3, false));
+        type_keys(&mut editor, ":e!\n");
+        assert_eq!(editor.buffer.filetype, "rust");
+        assert_eq!((editor.tab_width(), editor.expandtab()), (3, false));
+    }
+
+    #[test]
+    fn a_partly_loaded_file_can_only_be_written_elsewhere() {
+        let path = temp_file("large.txt", "abc\n");
+        let copy = path.with_file_name("large_start.txt");
+        let _ = fs::remove_file(&copy);
+        let mut editor = Editor::new(Config::default());
+        editor.edit(path.clone()).unwrap();
+        editor.buffer.partial = true;
+        type_keys(&mut editor, "x:w\n");
+        assert!(editor.status_message.as_ref().unwrap().contains("Only part of the file is loaded"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc\n");
+        type_keys(&mut editor, &format!(":w {}\n", copy.display()));
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "bc\n");
+    }
+
+    #[test]
+    fn an_unnamed_buffer_takes_the_name_it_is_written_to() {
+        let missing = std::env::temp_dir().join("text_editor-no-such-dir").join("file.rs");
+        let mut editor = editor_with(&["fn main() {}"]);
+        type_keys(&mut editor, &format!(":w {}\n", missing.display()));
+        assert!(editor.buffer.filename.is_none());
+        assert!(editor.status_message.as_ref().unwrap().starts_with("Error writing file"));
+
+        let path = temp_file("named.rs", "");
+        type_keys(&mut editor, &format!(":w {}\n", path.display()));
+        assert_eq!(editor.buffer.filename.as_ref(), Some(&path));
+        assert_eq!(editor.buffer.filetype, "rust");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+    }
+
+    #[test]
+    fn a_bare_read_reads_the_file_even_if_its_name_starts_with_a_bang() {
+        let path = temp_file("!echo ran", "abc\n");
+        let mut editor = Editor::new(Config::default());
+        editor.edit(path).unwrap();
+        type_keys(&mut editor, ":r\n");
+        assert_eq!(editor.buffer.content.to_vec(), ["abc", "abc"]);
+    }
+
+    #[test]
+    fn the_word_count_follows_edits_and_undo() {
+        let mut editor = editor_with(&["one two", "three"]);
+        type_keys(&mut editor, ":wc\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Buffer: 2 lines, 3 words, 12 characters"));
+        type_keys(&mut editor, "A four\x1b:wc\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Buffer: 2 lines, 4 words, 17 characters"));
+        type_keys(&mut editor, "u:wc\n");
+        assert_eq!(editor.status_message.as_deref(), Some("Buffer: 2 lines, 3 words, 12 characters"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_file_list_follows_links() {
+        let dir = std::env::temp_dir().join(format!("text_editor-test-{}", std::process::id())).join("links");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/inner.txt"), "").unwrap();
+        std::os::unix::fs::symlink("real", dir.join("dir_link")).unwrap();
+        std::os::unix::fs::symlink("real/inner.txt", dir.join("file_link")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("broken_link")).unwrap();
+        assert_eq!(
+            finder::files(&dir),
+            ["dir_link/inner.txt", "file_link", "real/inner.txt"]
+        );
+    }
+
+    fn editor_with_snippet(trigger: &str, body: &str) -> Editor {
+        let mut editor = editor_with(&[""]);
+        editor.snippets.insert(("all".to_string(), trigger.to_string()), body.to_string());
+        editor
+    }
+
+    #[test]
+    fn typing_at_a_snippet_stop_keeps_later_stops_in_place() {
+        let mut editor = editor_with_snippet("for", "for $2 in $1");
+        type_keys(&mut editor, "ifor\txs");
+        assert_eq!(editor.buffer.cursor_x, 10);
+        type_keys(&mut editor, "\tx");
+        assert_eq!(editor.buffer.content[0], "for x in xs");
+        type_keys(&mut editor, "\t");
+        assert_eq!(editor.buffer.cursor_x, 11);
+    }
+
+    #[test]
+    fn snippet_stops_follow_backspace_and_enter() {
+        let mut editor = editor_with_snippet("pair", "($1, $2) $0");
+        type_keys(&mut editor, "ipair\taxy\x08\x08\n\tb\tc");
+        assert_eq!(editor.buffer.content.to_vec(), ["(a", ", b) c"]);
+    }
+
+    #[test]
+    fn snippet_stops_end_with_the_insert() {
+        let mut editor = editor_with_snippet("pair", "($1, $2)");
+        type_keys(&mut editor, "ipair\t\x1b");
+        assert!(editor.snippet_stops.is_empty());
+        type_keys(&mut editor, "A\t");
+        assert_eq!(editor.buffer.cursor_y, 0);
+        assert!(editor.buffer.content[0].starts_with("(, )") && editor.buffer.content[0].len() > 4);
+    }
+
+    #[test]
+    fn changes_surrounding_characters() {
+        let mut editor = editor_with(&["say (hi)"]);
+        type_keys(&mut editor, "6lcs([");
+        assert_eq!(editor.buffer.content.to_vec(), ["say [hi]"]);
+    }
+
+    #[test]
+    fn change_surround_with_an_unknown_character_does_nothing() {
+        let mut editor = editor_with(&["say (hi)"]);
+        type_keys(&mut editor, "6lcsx");
+        assert_eq!(editor.status_message.as_deref(), Some("Not a surrounding character: x"));
+        assert!(editor.pending_keys.is_empty());
+        // The next keys are ordinary commands again
+        type_keys(&mut editor, "x");
+        assert_eq!(editor.buffer.content.to_vec(), ["say (h)"]);
+    }
+}
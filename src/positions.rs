@@ -0,0 +1,70 @@
+//! Where the cursor was in each file when it was last opened, saved or left,
+//! kept in `~/.cache/text_editor/positions` so reopening a file picks up
+//! from there. The same list, newest first, is what `:oldfiles` offers.
+//!
+//! Each line holds the line, column and absolute path of one file, the most
+//! recently used first.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files beyond this many are forgotten, oldest first.
+const LIMIT: usize = 100;
+
+/// The position last recorded for the file at `path`.
+pub fn load(path: &Path) -> Option<(usize, usize)> {
+    let path = fs::canonicalize(path).ok()?;
+    read_entries()
+        .into_iter()
+        .find(|(entry, _)| *entry == path)
+        .map(|(_, pos)| pos)
+}
+
+/// The files recorded, most recently used first.
+pub fn recent() -> Vec<PathBuf> {
+    read_entries().into_iter().map(|(path, _)| path).collect()
+}
+
+/// Records the cursor position of each of `files`, moving them to the front.
+pub fn save(files: &[(&Path, (usize, usize))]) -> io::Result<()> {
+    let file = store_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No cache directory"))?;
+    let mut entries: Vec<(PathBuf, (usize, usize))> = files
+        .iter()
+        .filter_map(|&(path, pos)| Some((fs::canonicalize(path).ok()?, pos)))
+        .collect();
+    for entry in read_entries() {
+        if !entries.iter().any(|(path, _)| *path == entry.0) {
+            entries.push(entry);
+        }
+    }
+    entries.truncate(LIMIT);
+    let out: String = entries
+        .iter()
+        .map(|(path, (y, x))| format!("{} {} {}\n", y, x, path.display()))
+        .collect();
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(file, out)
+}
+
+/// The stored entries, skipping any line that doesn't parse.
+fn read_entries() -> Vec<(PathBuf, (usize, usize))> {
+    let Some(text) = store_path().and_then(|file| fs::read_to_string(file).ok()) else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let y = fields.next()?.parse().ok()?;
+            let x = fields.next()?.parse().ok()?;
+            Some((PathBuf::from(fields.next()?), (y, x)))
+        })
+        .collect()
+}
+
+fn store_path() -> Option<PathBuf> {
+    Some(crate::cache_dir()?.join("positions"))
+}
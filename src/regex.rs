@@ -0,0 +1,696 @@
+//! Regular expressions for `/`, `:s` and `:g`.
+//!
+//! The syntax is the usual extended one: `.`, `[...]` classes, `\d`, `\w`,
+//! `\s` and their negations, the anchors `^`, `$`, `\b`, `\<` and `\>`,
+//! `*`, `+`, `?` and `{n,m}` repeats (lazy with a trailing `?`), `(...)`
+//! capture groups, `(?:...)` and `|`. `\t` matches a tab. Patterns are
+//! matched against one line at a time, so `\n` is refused rather than
+//! taken as a plain `n`. Patterns compile to a small instruction set for a
+//! backtracking matcher that remembers which states it has already tried,
+//! so no pattern takes more than line length times pattern size steps.
+//! Patterns without special characters skip all that and are searched for
+//! as plain strings.
+//!
+//! Positions are byte offsets into the searched line.
+
+use std::cell::RefCell;
+
+const SPECIAL: &[char] = &['.', '[', ']', '(', ')', '*', '+', '?', '{', '}', '|', '^', '$', '\\'];
+
+/// Why a `\n` in a pattern is refused.
+const NEWLINE: &str = "Patterns match within a line, so \\n never matches";
+
+/// Patterns compiling to more instructions than this are refused. Each
+/// repeat copies what it repeats, so nested counts multiply.
+const MAX_PROGRAM: usize = 4096;
+
+pub struct Regex {
+    matcher: Matcher,
+    ignore_case: bool,
+    // Whether `$1`-style references in replacements are expanded
+    regex: bool,
+    groups: usize,
+    // Kept between searches so repeated ones along a line allocate once
+    scratch: RefCell<Scratch>,
+}
+
+/// The matcher's record of explored states: one bit per position and
+/// instruction, position major so a search touches only the stretch of
+/// line it looks at. `touched` lists the nonzero words, to clear afterwards.
+#[derive(Default)]
+struct Scratch {
+    visited: Vec<u64>,
+    touched: Vec<usize>,
+}
+
+enum Matcher {
+    Literal(String),
+    Program(Vec<Inst>),
+}
+
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assert),
+    // Try the first target, and the second if that fails
+    Split(usize, usize),
+    Jump(usize),
+    // Record the current position in a capture slot
+    Save(usize),
+    Match,
+}
+
+#[derive(Clone, Copy)]
+enum Assert {
+    Start,
+    End,
+    WordBoundary,
+    WordStart,
+    WordEnd,
+}
+
+#[derive(Clone)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+#[derive(Clone)]
+enum ClassItem {
+    Range(char, char),
+    // One of `d`, `w`, `s` or their uppercase negations
+    Named(char),
+}
+
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assert),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+impl Regex {
+    /// Compiles `pattern`, or describes what is wrong with it.
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+        if !pattern.contains(SPECIAL) {
+            return Ok(Regex { regex: true, ..Regex::literal(pattern, ignore_case) });
+        }
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0 };
+        let node = parser.parse_alternate()?;
+        if parser.pos < parser.chars.len() {
+            return Err("Unmatched )".to_string());
+        }
+        if program_size(&node) > MAX_PROGRAM {
+            return Err("Pattern too large".to_string());
+        }
+        let mut program = vec![Inst::Save(0)];
+        compile(&node, &mut program);
+        program.push(Inst::Save(1));
+        program.push(Inst::Match);
+        Ok(Regex {
+            matcher: Matcher::Program(program),
+            ignore_case,
+            regex: true,
+            groups: parser.groups,
+            scratch: RefCell::default(),
+        })
+    }
+
+    /// A pattern that matches `text` exactly, special characters and all.
+    pub fn literal(text: &str, ignore_case: bool) -> Regex {
+        Regex {
+            matcher: Matcher::Literal(text.to_string()),
+            ignore_case,
+            regex: false,
+            groups: 0,
+            scratch: RefCell::default(),
+        }
+    }
+
+    /// The leftmost match starting at or after byte `from` of `text`.
+    pub fn find_at(&self, text: &str, from: usize) -> Option<(usize, usize)> {
+        self.captures_at(text, from).and_then(|groups| groups[0])
+    }
+
+    /// Like `find_at`, but also returns where each capture group matched,
+    /// with the whole match as group 0.
+    pub fn captures_at(&self, text: &str, from: usize) -> Option<Vec<Option<(usize, usize)>>> {
+        let program = match &self.matcher {
+            Matcher::Literal(literal) => {
+                return find_literal(text, literal, from, self.ignore_case).map(|found| vec![Some(found)]);
+            }
+            Matcher::Program(program) => program,
+        };
+        let mut scratch = self.scratch.borrow_mut();
+        let Scratch { visited, touched } = &mut *scratch;
+        // Only grown, never shrunk: the zeroed memory is not touched until
+        // a search reaches it, and every search leaves it zeroed again
+        let words = (program.len() * (text.len() + 1)).div_ceil(64);
+        if visited.len() < words {
+            *visited = vec![0; words];
+        }
+        let mut runner = Runner {
+            program,
+            text,
+            ignore_case: self.ignore_case,
+            visited,
+            touched,
+            slots: vec![None; (self.groups + 1) * 2],
+            stack: Vec::new(),
+        };
+        let starts = text[from..].char_indices().map(|(i, _)| from + i).chain([text.len()]);
+        let mut found = None;
+        for start in starts {
+            runner.slots.fill(None);
+            if runner.run(start) {
+                found = Some(runner.slots.chunks(2).map(|pair| Some((pair[0]?, pair[1]?))).collect());
+                break;
+            }
+        }
+        // States on the path to a match did not fail, so a later search
+        // must not find them marked
+        for word in touched.drain(..) {
+            visited[word] = 0;
+        }
+        found
+    }
+
+    /// The text to put in place of a match: `replacement` with `\1` or `$1`
+    /// replaced by what group 1 matched, and so on. `\\` and `$$` stand for
+    /// themselves and `\t` for a tab; any other backslash is kept as typed,
+    /// `\n` included, since a replacement cannot split a line. Replacements
+    /// for literal patterns have no groups to refer to, so only `\\` is
+    /// unescaped in them.
+    pub fn expand(&self, text: &str, groups: &[Option<(usize, usize)>], replacement: &str) -> String {
+        let mut out = String::with_capacity(replacement.len());
+        let mut chars = replacement.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek().copied()) {
+                ('\\', Some('\\')) if !self.regex => {
+                    chars.next();
+                    out.push(c);
+                }
+                _ if !self.regex => out.push(c),
+                ('\\' | '$', Some(d)) if d.is_ascii_digit() => {
+                    chars.next();
+                    let group = d.to_digit(10).unwrap() as usize;
+                    if let Some(Some((start, end))) = groups.get(group) {
+                        out.push_str(&text[*start..*end]);
+                    }
+                }
+                ('\\', Some('\\')) | ('$', Some('$')) => {
+                    chars.next();
+                    out.push(c);
+                }
+                ('\\', Some('t')) => {
+                    chars.next();
+                    out.push('\t');
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_alternate(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.eat('|') {
+            branches.push(self.parse_concat()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { Node::Alternate(branches) })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let mut node = self.parse_atom()?;
+            while let Some((min, max)) = self.parse_repeat()? {
+                let greedy = !self.eat('?');
+                node = Node::Repeat { node: Box::new(node), min, max, greedy };
+            }
+            nodes.push(node);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '(' => {
+                let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.parse_alternate()?;
+                if !self.eat(')') {
+                    return Err("Unmatched (".to_string());
+                }
+                Node::Group(Box::new(inner), index)
+            }
+            '[' => Node::Class(self.parse_class()?),
+            '.' => Node::Any,
+            '^' => Node::Assert(Assert::Start),
+            '$' => Node::Assert(Assert::End),
+            '*' | '+' | '?' => return Err(format!("Nothing to repeat before {}", c)),
+            '\\' => {
+                let Some(escaped) = self.peek() else {
+                    return Err("Trailing \\".to_string());
+                };
+                self.pos += 1;
+                match escaped {
+                    'd' | 'D' | 'w' | 'W' | 's' | 'S' => {
+                        Node::Class(Class { negated: false, items: vec![ClassItem::Named(escaped)] })
+                    }
+                    'b' => Node::Assert(Assert::WordBoundary),
+                    '<' => Node::Assert(Assert::WordStart),
+                    '>' => Node::Assert(Assert::WordEnd),
+                    't' => Node::Char('\t'),
+                    'n' => return Err(NEWLINE.to_string()),
+                    c => Node::Char(c),
+                }
+            }
+            c => Node::Char(c),
+        })
+    }
+
+    /// Reads a `*`, `+`, `?` or `{n,m}` after an atom. A `{` that does not
+    /// start a valid count is an ordinary character.
+    fn parse_repeat(&mut self) -> Result<Option<(usize, Option<usize>)>, String> {
+        let counts = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let rest: String = self.chars[self.pos + 1..].iter().collect();
+                let Some(close) = rest.find('}') else {
+                    return Ok(None);
+                };
+                let inside = &rest[..close];
+                let number = |s: &str| s.parse::<usize>().ok();
+                let counts = match inside.split_once(',') {
+                    None => number(inside).map(|n| (n, Some(n))),
+                    Some((min, "")) => number(min).map(|min| (min, None)),
+                    Some(("", max)) => number(max).map(|max| (0, Some(max))),
+                    Some((min, max)) => number(min).zip(number(max)).map(|(min, max)| (min, Some(max))),
+                };
+                let Some(counts) = counts else {
+                    return Ok(None);
+                };
+                if counts.1.is_some_and(|max| max < counts.0) {
+                    return Err(format!("Invalid repeat {{{}}}", inside));
+                }
+                self.pos += inside.chars().count() + 1;
+                counts
+            }
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+        Ok(Some(counts))
+    }
+
+    /// Reads a `[...]` class; the `[` is already consumed. A `]` right
+    /// after the opening bracket is part of the class.
+    fn parse_class(&mut self) -> Result<Class, String> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return Err("Unmatched [".to_string());
+            };
+            self.pos += 1;
+            if c == ']' && !first {
+                return Ok(Class { negated, items });
+            }
+            first = false;
+            let low = match c {
+                '\\' => {
+                    let Some(escaped) = self.peek() else {
+                        return Err("Unmatched [".to_string());
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        'd' | 'D' | 'w' | 'W' | 's' | 'S' => {
+                            items.push(ClassItem::Named(escaped));
+                            continue;
+                        }
+                        't' => '\t',
+                        'n' => return Err(NEWLINE.to_string()),
+                        c => c,
+                    }
+                }
+                c => c,
+            };
+            let is_range = self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']');
+            if !is_range {
+                items.push(ClassItem::Range(low, low));
+                continue;
+            }
+            self.pos += 1;
+            let mut high = self.peek().unwrap();
+            self.pos += 1;
+            if high == '\\' {
+                high = self.peek().ok_or("Unmatched [")?;
+                self.pos += 1;
+            }
+            if high < low {
+                return Err(format!("Invalid range {}-{}", low, high));
+            }
+            items.push(ClassItem::Range(low, high));
+        }
+    }
+}
+
+/// How many instructions `compile` turns `node` into, saturating rather
+/// than overflowing for absurd counts.
+fn program_size(node: &Node) -> usize {
+    match node {
+        Node::Char(_) | Node::Any | Node::Class(_) | Node::Assert(_) => 1,
+        Node::Group(inner, index) => program_size(inner).saturating_add(if index.is_some() { 2 } else { 0 }),
+        Node::Concat(nodes) => nodes.iter().fold(0, |size, node| size.saturating_add(program_size(node))),
+        Node::Alternate(branches) => branches
+            .iter()
+            .fold(2 * (branches.len() - 1), |size, branch| size.saturating_add(program_size(branch))),
+        Node::Repeat { node, min, max, .. } => {
+            let body = program_size(node);
+            let (optional, each) = match max {
+                Some(max) => (max - min, body.saturating_add(1)),
+                None => (1, body.saturating_add(2)),
+            };
+            body.saturating_mul(*min).saturating_add(optional.saturating_mul(each))
+        }
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Assert(assert) => program.push(Inst::Assert(*assert)),
+        Node::Group(inner, index) => {
+            if let Some(index) = index {
+                program.push(Inst::Save(index * 2));
+            }
+            compile(inner, program);
+            if let Some(index) = index {
+                program.push(Inst::Save(index * 2 + 1));
+            }
+        }
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternate(branches) => {
+            // Each branch but the last is tried first, then jumps past the rest
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 == branches.len() {
+                    compile(branch, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(branch, program);
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                let next = program.len();
+                program[split] = Inst::Split(split + 1, next);
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat { node, min, max, greedy } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            let optional = match max {
+                Some(max) => max - min,
+                None => 1,
+            };
+            for _ in 0..optional {
+                let split = program.len();
+                program.push(Inst::Match);
+                compile(node, program);
+                if max.is_none() {
+                    program.push(Inst::Jump(split));
+                }
+                // The split either tries the body or skips it, greedy first
+                let (body, skip) = (split + 1, program.len());
+                program[split] = if *greedy { Inst::Split(body, skip) } else { Inst::Split(skip, body) };
+            }
+        }
+    }
+}
+
+enum Job {
+    Explore(usize, usize),
+    Restore(usize, Option<usize>),
+}
+
+struct Runner<'a> {
+    program: &'a [Inst],
+    text: &'a str,
+    ignore_case: bool,
+    visited: &'a mut [u64],
+    touched: &'a mut Vec<usize>,
+    slots: Vec<Option<usize>>,
+    stack: Vec<Job>,
+}
+
+impl Runner<'_> {
+    /// Whether the program matches starting at byte `start`, leaving the
+    /// capture positions in `slots`. Visited states stay marked across
+    /// calls: a state that failed for one start fails for any other.
+    fn run(&mut self, start: usize) -> bool {
+        self.stack.clear();
+        self.stack.push(Job::Explore(0, start));
+        while let Some(job) = self.stack.pop() {
+            let (mut pc, mut pos) = match job {
+                Job::Restore(slot, old) => {
+                    self.slots[slot] = old;
+                    continue;
+                }
+                Job::Explore(pc, pos) => (pc, pos),
+            };
+            loop {
+                let state = pos * self.program.len() + pc;
+                let (word, bit) = (state / 64, 1 << (state % 64));
+                if self.visited[word] & bit != 0 {
+                    break;
+                }
+                if self.visited[word] == 0 {
+                    self.touched.push(word);
+                }
+                self.visited[word] |= bit;
+                let next = self.text[pos..].chars().next();
+                match &self.program[pc] {
+                    Inst::Char(expected) => match next {
+                        Some(c) if chars_equal(c, *expected, self.ignore_case) => {
+                            pc += 1;
+                            pos += c.len_utf8();
+                        }
+                        _ => break,
+                    },
+                    Inst::Any => match next {
+                        Some(c) => {
+                            pc += 1;
+                            pos += c.len_utf8();
+                        }
+                        None => break,
+                    },
+                    Inst::Class(class) => match next {
+                        Some(c) if class.matches(c, self.ignore_case) => {
+                            pc += 1;
+                            pos += c.len_utf8();
+                        }
+                        _ => break,
+                    },
+                    Inst::Assert(assert) => {
+                        if !self.check(*assert, pos) {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    Inst::Split(first, second) => {
+                        self.stack.push(Job::Explore(*second, pos));
+                        pc = *first;
+                    }
+                    Inst::Jump(target) => pc = *target,
+                    Inst::Save(slot) => {
+                        self.stack.push(Job::Restore(*slot, self.slots[*slot]));
+                        self.slots[*slot] = Some(pos);
+                        pc += 1;
+                    }
+                    Inst::Match => return true,
+                }
+            }
+        }
+        false
+    }
+
+    fn check(&self, assert: Assert, pos: usize) -> bool {
+        let before = self.text[..pos].chars().next_back().is_some_and(is_word_char);
+        let after = self.text[pos..].chars().next().is_some_and(is_word_char);
+        match assert {
+            Assert::Start => pos == 0,
+            Assert::End => pos == self.text.len(),
+            Assert::WordBoundary => before != after,
+            Assert::WordStart => !before && after,
+            Assert::WordEnd => before && !after,
+        }
+    }
+}
+
+impl Class {
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        let contains = |c: char| {
+            self.items.iter().any(|item| match *item {
+                ClassItem::Range(low, high) => low <= c && c <= high,
+                ClassItem::Named(name) => named_class(name, c),
+            })
+        };
+        let found = contains(c)
+            || ignore_case
+                && (c.to_lowercase().any(contains) || c.to_uppercase().any(contains));
+        found != self.negated
+    }
+}
+
+fn named_class(name: char, c: char) -> bool {
+    match name {
+        'd' => c.is_ascii_digit(),
+        'D' => !c.is_ascii_digit(),
+        'w' => is_word_char(c),
+        'W' => !is_word_char(c),
+        's' => c.is_whitespace(),
+        _ => !c.is_whitespace(),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn chars_equal(a: char, b: char, ignore_case: bool) -> bool {
+    a == b || ignore_case && a.to_lowercase().eq(b.to_lowercase())
+}
+
+/// Byte range of the first occurrence of `pattern` in `line` at or after
+/// byte `from`. An empty pattern matches nothing.
+fn find_literal(line: &str, pattern: &str, from: usize, ignore_case: bool) -> Option<(usize, usize)> {
+    if pattern.is_empty() {
+        return None;
+    }
+    if !ignore_case {
+        return line[from..]
+            .find(pattern)
+            .map(|i| (from + i, from + i + pattern.len()));
+    }
+    for (i, _) in line[from..].char_indices() {
+        let start = from + i;
+        let mut rest = line[start..].char_indices();
+        let mut end = start;
+        let matched = pattern.chars().all(|p| match rest.next() {
+            Some((j, c)) if chars_equal(c, p, true) => {
+                end = start + j + c.len_utf8();
+                true
+            }
+            _ => false,
+        });
+        if matched {
+            return Some((start, end));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program_len(pattern: &str) -> usize {
+        match Regex::new(pattern, false).unwrap().matcher {
+            Matcher::Program(program) => program.len(),
+            Matcher::Literal(_) => panic!("{} compiled to a literal", pattern),
+        }
+    }
+
+    #[test]
+    fn program_size_matches_the_compiled_program() {
+        for pattern in ["a.b", "(a|bc|d)+x", "(?:ab){2,5}?", "[a-z]{3,}\\d*", "^(x(y)?)*$"] {
+            let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0 };
+            let node = parser.parse_alternate().unwrap();
+            // `new` wraps the program in two saves and a match
+            assert_eq!(program_size(&node) + 3, program_len(pattern), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn nested_repeats_are_limited_as_a_whole() {
+        assert!(Regex::new("x{1000}", false).is_ok());
+        assert!(Regex::new("(x{1000}){1000}", false).is_err());
+        assert!(Regex::new("(a{4000}){4000}{4000}", false).is_err());
+    }
+
+    #[test]
+    fn searches_along_a_line_find_every_match() {
+        let regex = Regex::new("a+|b", false).unwrap();
+        let line = "aab b aaa";
+        let mut from = 0;
+        let mut found = Vec::new();
+        while let Some((start, end)) = regex.find_at(line, from) {
+            found.push(&line[start..end]);
+            from = end;
+        }
+        assert_eq!(found, ["aa", "b", "b", "aaa"]);
+        // Nothing explored is left marked for the next line
+        let scratch = regex.scratch.borrow();
+        assert!(scratch.visited.iter().all(|&word| word == 0) && scratch.touched.is_empty());
+    }
+
+    #[test]
+    fn tab_escapes_and_newlines() {
+        let regex = Regex::new("a\\t[\\t]b", false).unwrap();
+        assert_eq!(regex.find_at("xa\t\tb", 0), Some((1, 5)));
+        assert_eq!(regex.expand("", &[], "(\\t\\n)"), "(\t\\n)");
+        assert!(Regex::new("a\\n", false).is_err());
+        assert!(Regex::new("[\\n]", false).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+//! The lines of a buffer, held as a rope: a balanced tree whose leaves are
+//! runs of lines, so inserting or removing a line costs the height of the
+//! tree rather than moving every line after it.
+//!
+//! Nodes are shared between copies and only copied when one of them
+//! changes, so cloning a rope, as every undo snapshot does, is cheap and an
+//! edit afterwards copies just the path down to the line it touches.
+//!
+//! Each line is still a `String`, so an edit within a line costs the length
+//! of that line, and finding a line by number walks the tree where a `Vec`
+//! would index straight into it. `benches/rope.rs` measures both sides.
+//!
+//! Indices are line numbers, and the methods mirror `Vec<String>`'s.
+
+use std::fmt;
+use std::ops::{Bound, Index, IndexMut, RangeBounds};
+use std::rc::Rc;
+
+/// Leaves split in two when they grow past this many lines.
+const MAX_LEAF: usize = 64;
+
+/// Branches split in two when they grow past this many children.
+const MAX_CHILDREN: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct Rope {
+    root: Rc<Node>,
+}
+
+#[derive(Clone)]
+enum Node {
+    Leaf(Vec<String>),
+    // Children and the number of lines below them
+    Branch(Vec<Rc<Node>>, usize),
+}
+
+impl Default for Node {
+    fn default() -> Node {
+        Node::Leaf(Vec::new())
+    }
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(lines) => lines.len(),
+            Node::Branch(_, len) => *len,
+        }
+    }
+
+    /// Inserts `line` at `i`. If that makes the node too big it keeps the
+    /// first half and returns the second, to become its next sibling.
+    fn insert(&mut self, i: usize, line: String) -> Option<Node> {
+        match self {
+            Node::Leaf(lines) => {
+                lines.insert(i, line);
+                (lines.len() > MAX_LEAF).then(|| Node::Leaf(lines.split_off(lines.len() / 2)))
+            }
+            Node::Branch(children, len) => {
+                *len += 1;
+                let (index, i) = find_child(children, i, true);
+                if let Some(right) = Rc::make_mut(&mut children[index]).insert(i, line) {
+                    children.insert(index + 1, Rc::new(right));
+                }
+                if children.len() <= MAX_CHILDREN {
+                    return None;
+                }
+                let right = children.split_off(children.len() / 2);
+                let right_len = right.iter().map(|child| child.len()).sum();
+                *len -= right_len;
+                Some(Node::Branch(right, right_len))
+            }
+        }
+    }
+
+    /// Removes and returns line `i`, dropping children left empty.
+    fn remove(&mut self, i: usize) -> String {
+        match self {
+            Node::Leaf(lines) => lines.remove(i),
+            Node::Branch(children, len) => {
+                *len -= 1;
+                let (index, i) = find_child(children, i, false);
+                let child = Rc::make_mut(&mut children[index]);
+                let line = child.remove(i);
+                if child.len() == 0 {
+                    children.remove(index);
+                }
+                line
+            }
+        }
+    }
+}
+
+/// The child of a branch holding line `i`, and the line's index within it.
+/// With `append`, a line just past a child's end is taken to be in it, for
+/// inserting there.
+fn find_child(children: &[Rc<Node>], mut i: usize, append: bool) -> (usize, usize) {
+    for (index, child) in children.iter().enumerate() {
+        let len = child.len();
+        if i < len || (append && i == len) {
+            return (index, i);
+        }
+        i -= len;
+    }
+    panic!("line index out of range");
+}
+
+impl Rope {
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, mut i: usize) -> Option<&String> {
+        let mut node = &*self.root;
+        loop {
+            match node {
+                Node::Leaf(lines) => return lines.get(i),
+                Node::Branch(children, len) => {
+                    if i >= *len {
+                        return None;
+                    }
+                    let (index, within) = find_child(children, i, false);
+                    node = &children[index];
+                    i = within;
+                }
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, mut i: usize) -> Option<&mut String> {
+        if i >= self.len() {
+            return None;
+        }
+        let mut node = Rc::make_mut(&mut self.root);
+        loop {
+            match node {
+                Node::Leaf(lines) => return lines.get_mut(i),
+                Node::Branch(children, _) => {
+                    let (index, within) = find_child(children, i, false);
+                    node = Rc::make_mut(&mut children[index]);
+                    i = within;
+                }
+            }
+        }
+    }
+
+    pub fn last(&self) -> Option<&String> {
+        self.len().checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    /// Inserts `line` before line `i`, or at the end when `i` is `len()`.
+    pub fn insert(&mut self, i: usize, line: String) {
+        assert!(i <= self.len(), "insertion index {} out of range", i);
+        let root = Rc::make_mut(&mut self.root);
+        if let Some(right) = root.insert(i, line) {
+            // The root split, so the tree grows a level
+            let left = std::mem::take(root);
+            let len = left.len() + right.len();
+            *root = Node::Branch(vec![Rc::new(left), Rc::new(right)], len);
+        }
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.insert(self.len(), line);
+    }
+
+    pub fn remove(&mut self, i: usize) -> String {
+        assert!(i < self.len(), "removal index {} out of range", i);
+        let root = Rc::make_mut(&mut self.root);
+        let line = root.remove(i);
+        // A root left with one child or none gives up its level
+        loop {
+            match root {
+                Node::Branch(children, _) if children.len() <= 1 => {
+                    *root = match children.pop() {
+                        Some(only) => Rc::try_unwrap(only).unwrap_or_else(|shared| (*shared).clone()),
+                        None => Node::default(),
+                    };
+                }
+                _ => break,
+            }
+        }
+        line
+    }
+
+    /// Replaces the lines in `range` with `lines`, returning the ones taken
+    /// out.
+    pub fn splice<R, I>(&mut self, range: R, lines: I) -> Vec<String>
+    where
+        R: RangeBounds<usize>,
+        I: IntoIterator<Item = String>,
+    {
+        let (start, end) = self.bounds(range);
+        let removed = (start..end).map(|_| self.remove(start)).collect();
+        for (offset, line) in lines.into_iter().enumerate() {
+            self.insert(start + offset, line);
+        }
+        removed
+    }
+
+    /// Removes the lines in `range`, returning them in order.
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> std::vec::IntoIter<String> {
+        self.splice(range, []).into_iter()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.range(..)
+    }
+
+    /// The lines in `range`, in order.
+    pub fn range<R: RangeBounds<usize>>(&self, range: R) -> Iter<'_> {
+        let (mut start, end) = self.bounds(range);
+        let remaining = end - start;
+        let mut stack = Vec::new();
+        let mut node = &*self.root;
+        let leaf = loop {
+            match node {
+                Node::Leaf(lines) => break lines[std::cmp::min(start, lines.len())..].iter(),
+                Node::Branch(children, _) => {
+                    let (index, within) = find_child(children, start, true);
+                    stack.push(children[index + 1..].iter());
+                    node = &children[index];
+                    start = within;
+                }
+            }
+        };
+        Iter { stack, leaf, remaining }
+    }
+
+    pub fn join(&self, separator: &str) -> String {
+        let mut out = String::new();
+        for (i, line) in self.iter().enumerate() {
+            if i > 0 {
+                out.push_str(separator);
+            }
+            out.push_str(line);
+        }
+        out
+    }
+
+    pub fn to_vec(&self) -> Vec<String> {
+        self.iter().cloned().collect()
+    }
+
+    fn bounds<R: RangeBounds<usize>>(&self, range: R) -> (usize, usize) {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(start <= end && end <= self.len(), "line range {}..{} out of range", start, end);
+        (start, end)
+    }
+}
+
+impl From<Vec<String>> for Rope {
+    /// Builds the tree bottom up from full leaves.
+    fn from(lines: Vec<String>) -> Rope {
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut lines = lines.into_iter().peekable();
+        while lines.peek().is_some() {
+            nodes.push(Node::Leaf(lines.by_ref().take(MAX_LEAF).collect()));
+        }
+        while nodes.len() > 1 {
+            let mut level = Vec::new();
+            let mut nodes_left = nodes.into_iter().peekable();
+            while nodes_left.peek().is_some() {
+                let children: Vec<Rc<Node>> = nodes_left.by_ref().take(MAX_CHILDREN).map(Rc::new).collect();
+                let len = children.iter().map(|child| child.len()).sum();
+                level.push(Node::Branch(children, len));
+            }
+            nodes = level;
+        }
+        Rope { root: Rc::new(nodes.pop().unwrap_or_default()) }
+    }
+}
+
+impl FromIterator<String> for Rope {
+    fn from_iter<I: IntoIterator<Item = String>>(lines: I) -> Rope {
+        Rope::from(lines.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl Index<usize> for Rope {
+    type Output = String;
+
+    fn index(&self, i: usize) -> &String {
+        let len = self.len();
+        self.get(i).unwrap_or_else(|| panic!("line {} out of range for {} lines", i, len))
+    }
+}
+
+impl IndexMut<usize> for Rope {
+    fn index_mut(&mut self, i: usize) -> &mut String {
+        let len = self.len();
+        self.get_mut(i).unwrap_or_else(|| panic!("line {} out of range for {} lines", i, len))
+    }
+}
+
+impl PartialEq for Rope {
+    fn eq(&self, other: &Rope) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a Rope {
+    type Item = &'a String;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The lines of a rope in order, walking its leaves left to right.
+pub struct Iter<'a> {
+    // Siblings still to visit at each level above the current leaf
+    stack: Vec<std::slice::Iter<'a, Rc<Node>>>,
+    leaf: std::slice::Iter<'a, String>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a String;
+
+    fn next(&mut self) -> Option<&'a String> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(line) = self.leaf.next() {
+                self.remaining -= 1;
+                return Some(line);
+            }
+            // Climb to the nearest unvisited sibling and down its left edge
+            loop {
+                let child = self.stack.last_mut()?.next();
+                match child.map(|child| &**child) {
+                    Some(Node::Leaf(lines)) => {
+                        self.leaf = lines.iter();
+                        break;
+                    }
+                    Some(Node::Branch(children, _)) => self.stack.push(children.iter()),
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("line {}", i)).collect()
+    }
+
+    /// Checks every node's cached length and that no node is over full.
+    fn check(node: &Node) -> usize {
+        match node {
+            Node::Leaf(lines) => {
+                assert!(lines.len() <= MAX_LEAF);
+                lines.len()
+            }
+            Node::Branch(children, len) => {
+                assert!(children.len() <= MAX_CHILDREN && !children.is_empty());
+                assert_eq!(children.iter().map(|child| check(child)).sum::<usize>(), *len);
+                *len
+            }
+        }
+    }
+
+    #[test]
+    fn builds_from_lines_and_reads_them_back() {
+        for n in [0, 1, MAX_LEAF, MAX_LEAF + 1, 5000] {
+            let rope = Rope::from(lines(n));
+            check(&rope.root);
+            assert_eq!(rope.len(), n);
+            assert_eq!(rope.to_vec(), lines(n));
+            assert_eq!(rope.get(n), None);
+            assert_eq!(rope.last(), lines(n).last());
+        }
+    }
+
+    #[test]
+    fn edits_match_a_vec() {
+        let mut rope = Rope::from(lines(300));
+        let mut vec = lines(300);
+        // A fixed pseudo-random sequence of edits all over the rope
+        let mut seed: u64 = 7;
+        let mut next = |bound: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        for step in 0..5000 {
+            match next(4) {
+                0 | 1 => {
+                    let i = next(vec.len() + 1);
+                    vec.insert(i, format!("new {}", step));
+                    rope.insert(i, format!("new {}", step));
+                }
+                2 if !vec.is_empty() => {
+                    let i = next(vec.len());
+                    assert_eq!(rope.remove(i), vec.remove(i));
+                }
+                _ if !vec.is_empty() => {
+                    let i = next(vec.len());
+                    vec[i].push('!');
+                    rope[i].push('!');
+                }
+                _ => {}
+            }
+        }
+        check(&rope.root);
+        assert_eq!(rope.to_vec(), vec);
+    }
+
+    #[test]
+    fn ranges_splices_and_drains_match_a_vec() {
+        let mut rope = Rope::from(lines(1000));
+        let mut vec = lines(1000);
+        let a: Vec<String> = rope.range(63..130).cloned().collect();
+        assert_eq!(a, vec[63..130]);
+        assert_eq!(rope.range(1000..).count(), 0);
+        assert_eq!(rope.range(64..=64).collect::<Vec<_>>(), [&vec[64]]);
+        let replacement = vec!["a".to_string(), "b".to_string()];
+        let removed = rope.splice(10..500, replacement.clone());
+        let expected: Vec<String> = vec.splice(10..500, replacement).collect();
+        assert_eq!(removed, expected);
+        let drained: Vec<String> = rope.drain(..300).collect();
+        assert_eq!(drained, vec.drain(..300).collect::<Vec<_>>());
+        check(&rope.root);
+        assert_eq!(rope.to_vec(), vec);
+        assert_eq!(rope.join("\n"), vec.join("\n"));
+    }
+
+    #[test]
+    fn removing_every_line_leaves_an_empty_rope() {
+        let mut rope = Rope::from(lines(2000));
+        while !rope.is_empty() {
+            rope.remove(rope.len() / 2);
+        }
+        check(&rope.root);
+        rope.push("again".to_string());
+        assert_eq!(rope.to_vec(), ["again"]);
+    }
+
+    #[test]
+    fn clones_are_unaffected_by_later_edits() {
+        let mut rope = Rope::from(lines(1000));
+        let snapshot = rope.clone();
+        rope[500].push_str(" changed");
+        rope.insert(0, "first".to_string());
+        rope.remove(999);
+        assert_eq!(snapshot.to_vec(), lines(1000));
+        assert_eq!(rope[501], "line 500 changed");
+    }
+}
@@ -0,0 +1,93 @@
+//! Sessions: the open files, their cursors and the window layout, saved with
+//! `:mksession` and restored with `:source` or `--session`.
+//!
+//! A session file holds a header line, then one line per file and one per
+//! window:
+//!
+//! ```text
+//! text_editor session 1
+//! layout vertical
+//! file 12 4 /home/me/src/main.rs
+//! window 0 12 4 1
+//! ```
+//!
+//! A file line gives its cursor line, column and absolute path. A window
+//! line gives the index of the file it shows, its cursor line and column,
+//! and whether it is the active window.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "text_editor session 1";
+
+/// Where `:mksession` and `--session` look when given no file.
+pub const DEFAULT_FILE: &str = "Session.txt";
+
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    /// Windows sit side by side rather than stacked.
+    pub vertical: bool,
+    /// Each file's path and cursor line and column.
+    pub files: Vec<(PathBuf, (usize, usize))>,
+    /// Each window's index into `files` and cursor line and column.
+    pub windows: Vec<(usize, (usize, usize))>,
+    /// The index of the active window.
+    pub current_window: usize,
+}
+
+/// Writes `session` to `path`.
+pub fn write(path: &Path, session: &Session) -> io::Result<()> {
+    let layout = if session.vertical { "vertical" } else { "horizontal" };
+    let mut out = format!("{}\nlayout {}\n", HEADER, layout);
+    for (file, (y, x)) in &session.files {
+        out.push_str(&format!("file {} {} {}\n", y, x, file.display()));
+    }
+    for (index, &(file, (y, x))) in session.windows.iter().enumerate() {
+        let active = (index == session.current_window) as u8;
+        out.push_str(&format!("window {} {} {} {}\n", file, y, x, active));
+    }
+    fs::write(path, out)
+}
+
+/// Reads the session saved in `path`.
+pub fn read(path: &Path) -> Result<Session, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(format!("{} is not a session file", path.display()));
+    }
+    let mut session = Session::default();
+    for (number, line) in lines.enumerate() {
+        let invalid = || format!("invalid session line {}: {}", number + 2, line);
+        let (kind, rest) = line.split_once(' ').ok_or_else(invalid)?;
+        match kind {
+            "layout" => session.vertical = rest == "vertical",
+            "file" => {
+                let mut fields = rest.splitn(3, ' ');
+                let y = number_field(fields.next()).ok_or_else(invalid)?;
+                let x = number_field(fields.next()).ok_or_else(invalid)?;
+                let file = fields.next().ok_or_else(invalid)?;
+                session.files.push((PathBuf::from(file), (y, x)));
+            }
+            "window" => {
+                let mut fields = rest.split(' ').map(Some);
+                let mut field = || number_field(fields.next().flatten()).ok_or_else(invalid);
+                let (file, y, x, active) = (field()?, field()?, field()?, field()?);
+                if file >= session.files.len() {
+                    return Err(invalid());
+                }
+                if active == 1 {
+                    session.current_window = session.windows.len();
+                }
+                session.windows.push((file, (y, x)));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(session)
+}
+
+fn number_field(field: Option<&str>) -> Option<usize> {
+    field?.parse().ok()
+}
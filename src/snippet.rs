@@ -0,0 +1,95 @@
+//! Snippets: a word typed in insert mode that Tab expands into a longer
+//! text, set in the config file under `[snippets.<filetype>]`, or
+//! `[snippets.all]` for every file type.
+//!
+//! In the text, `$1`, `$2` and so on mark tab stops the cursor visits in
+//! order on each further Tab, and `$0` the last one; without a `$0` the
+//! last stop is the end of the snippet. `$$` is a literal `$`.
+
+/// Expands snippet `body`: `indent` goes before each line after the first
+/// and `tab` in place of each tab. Returns the lines and the tab stops, in
+/// visiting order, as line and character column within them.
+pub fn expand(body: &str, indent: &str, tab: &str) -> (Vec<String>, Vec<(usize, usize)>) {
+    let mut lines = vec![String::new()];
+    let mut col = 0;
+    let mut stops: Vec<(u32, (usize, usize))> = Vec::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                lines.push(indent.to_string());
+                col = indent.chars().count();
+            }
+            '\t' => {
+                lines.last_mut().unwrap().push_str(tab);
+                col += tab.chars().count();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                lines.last_mut().unwrap().push('$');
+                col += 1;
+            }
+            '$' if chars.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                let mut number = 0;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    number = number * 10 + digit;
+                    chars.next();
+                }
+                // A stop used twice is visited where it first appears
+                if stops.iter().all(|&(n, _)| n != number) {
+                    stops.push((number, (lines.len() - 1, col)));
+                }
+            }
+            c => {
+                lines.last_mut().unwrap().push(c);
+                col += 1;
+            }
+        }
+    }
+    if stops.iter().all(|&(n, _)| n != 0) {
+        stops.push((0, (lines.len() - 1, col)));
+    }
+    // `$0` is visited last
+    stops.sort_by_key(|&(number, _)| if number == 0 { u32::MAX } else { number });
+    (lines, stops.into_iter().map(|(_, stop)| stop).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_are_visited_in_number_order_with_zero_last() {
+        let (lines, stops) = expand("$0 $2 $1", "", "\t");
+        assert_eq!(lines, ["  "]);
+        assert_eq!(stops, [(0, 2), (0, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn without_zero_the_last_stop_is_the_end() {
+        let (lines, stops) = expand("f($1)", "", "\t");
+        assert_eq!(lines, ["f()"]);
+        assert_eq!(stops, [(0, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn double_dollar_is_a_literal_dollar() {
+        let (lines, stops) = expand("$$x $$1", "", "\t");
+        assert_eq!(lines, ["$x $1"]);
+        assert_eq!(stops, [(0, 5)]);
+    }
+
+    #[test]
+    fn a_repeated_stop_is_visited_where_it_first_appears() {
+        let (lines, stops) = expand("$1 = $1 + $10", "", "\t");
+        assert_eq!(lines, [" =  + "]);
+        assert_eq!(stops, [(0, 0), (0, 6), (0, 6)]);
+    }
+
+    #[test]
+    fn later_lines_are_indented_and_tabs_replaced() {
+        let (lines, stops) = expand("if $1 {\n\t$0\n}", "    ", "  ");
+        assert_eq!(lines, ["if  {", "      ", "    }"]);
+        assert_eq!(stops, [(0, 3), (1, 6)]);
+    }
+}
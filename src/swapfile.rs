@@ -0,0 +1,68 @@
+//! Unsaved changes kept next to the file being edited, as `.name.swp` the
+//! way vim does, so they survive the editor or terminal dying.
+//!
+//! A swap file holds a header line and then the buffer's lines. It is
+//! written after a pause in typing and removed once the file is saved or
+//! the editor exits cleanly, so one found on opening a file means changes
+//! were lost, or that the file is open elsewhere. A session that finds
+//! `.name.swp` taken writes its own as `.name.swo`, then `.name.swn` and
+//! so on, again like vim.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "text_editor swap 1";
+
+/// Records `content` as the unsaved state of a file in swap file `swap`.
+pub fn write(swap: &Path, content: &[String]) -> io::Result<()> {
+    let mut out = format!("{}\n", HEADER);
+    out.push_str(&content.join("\n"));
+    fs::write(swap, out)
+}
+
+/// The first swap file name for the file at `path` that is not in use.
+pub fn unused(path: &Path) -> io::Result<PathBuf> {
+    ('a'..='p')
+        .rev()
+        .map(|last| swap_name(path, last))
+        .find(|swap| swap.as_ref().is_ok_and(|swap| !swap.exists()))
+        .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::AlreadyExists, "Too many swap files")))
+}
+
+/// The lines recorded for the file at `path`, if a swap file exists.
+pub fn read(path: &Path) -> Option<Vec<String>> {
+    let stored = fs::read_to_string(swap_path(path).ok()?).ok()?;
+    let (header, text) = stored.split_once('\n')?;
+    if header != HEADER {
+        return None;
+    }
+    Some(text.split('\n').map(String::from).collect())
+}
+
+/// Whether the file at `path` has a swap file.
+pub fn exists(path: &Path) -> bool {
+    swap_path(path).is_ok_and(|swap| swap.exists())
+}
+
+/// Deletes swap file `swap`, if it is there.
+pub fn remove(swap: &Path) -> io::Result<()> {
+    match fs::remove_file(swap) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The usual swap file for `path`, `.name.swp`, where an earlier session
+/// or another editor on the file will have written.
+pub fn swap_path(path: &Path) -> io::Result<PathBuf> {
+    swap_name(path, 'p')
+}
+
+/// The swap file for `path` ending in `.sw` and `last`.
+fn swap_name(path: &Path, last: char) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No file name"))?;
+    Ok(path.with_file_name(format!(".{}.sw{}", name.to_string_lossy(), last)))
+}
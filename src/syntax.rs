@@ -0,0 +1,209 @@
+//! A lightweight, line-at-a-time syntax highlighter.
+//!
+//! Each line is tokenized on its own, so constructs spanning lines (block
+//! comments, multi-line strings) are only highlighted on the line they start.
+//! Markdown code fences are the exception: the caller says whether a line
+//! sits inside one.
+
+use crossterm::style::{Attribute, Color};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    Normal,
+    Keyword,
+    Type,
+    String,
+    Comment,
+    Number,
+    Heading,
+    Strong,
+    Emphasis,
+    Code,
+}
+
+impl Highlight {
+    /// The foreground color for this highlight, or `None` to keep the default.
+    pub fn color(self) -> Option<Color> {
+        match self {
+            Highlight::Normal => None,
+            Highlight::Keyword => Some(Color::Magenta),
+            Highlight::Type => Some(Color::Cyan),
+            Highlight::String => Some(Color::Green),
+            Highlight::Comment => Some(Color::DarkGrey),
+            Highlight::Number => Some(Color::Yellow),
+            Highlight::Heading => Some(Color::Blue),
+            Highlight::Strong | Highlight::Emphasis => None,
+            Highlight::Code => Some(Color::DarkYellow),
+        }
+    }
+
+    /// The text attribute for this highlight, if it has one.
+    pub fn attribute(self) -> Option<Attribute> {
+        match self {
+            Highlight::Heading | Highlight::Strong => Some(Attribute::Bold),
+            Highlight::Emphasis => Some(Attribute::Italic),
+            _ => None,
+        }
+    }
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+    "type", "unsafe", "use", "where", "while",
+];
+
+const RUST_TYPES: &[&str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+    "i64", "i128", "isize", "f32", "f64", "String", "Vec", "Option", "Result", "Box", "Some",
+    "None", "Ok", "Err",
+];
+
+/// Highlights one line of Rust, returning one entry per character.
+pub fn highlight_rust(line: &str) -> Vec<Highlight> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = vec![Highlight::Normal; chars.len()];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c == '/' && next == Some('/') {
+            out[i..].fill(Highlight::Comment);
+            break;
+        }
+        if c == '/' && next == Some('*') {
+            let end = find_seq(&chars, i + 2, &['*', '/']).map_or(chars.len(), |e| e + 2);
+            out[i..end].fill(Highlight::Comment);
+            i = end;
+        } else if c == '"' {
+            let end = string_end(&chars, i + 1, '"');
+            out[i..end].fill(Highlight::String);
+            i = end;
+        } else if c == '\'' && is_char_literal(&chars, i) {
+            let end = string_end(&chars, i + 1, '\'');
+            out[i..end].fill(Highlight::String);
+            i = end;
+        } else if c.is_ascii_digit() {
+            let end = run_end(&chars, i, |c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+            out[i..end].fill(Highlight::Number);
+            i = end;
+        } else if c.is_alphabetic() || c == '_' {
+            let end = run_end(&chars, i, |c| c.is_alphanumeric() || c == '_');
+            let word: String = chars[i..end].iter().collect();
+            // Macro invocations like `println!` read as keywords
+            let is_macro = chars.get(end) == Some(&'!') && chars.get(end + 1) != Some(&'=');
+            let kind = if RUST_KEYWORDS.contains(&word.as_str()) {
+                Highlight::Keyword
+            } else if RUST_TYPES.contains(&word.as_str()) {
+                Highlight::Type
+            } else if is_macro {
+                Highlight::Keyword
+            } else {
+                Highlight::Normal
+            };
+            let end = if is_macro { end + 1 } else { end };
+            out[i..end].fill(kind);
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Whether `line` opens or closes a Markdown code fence.
+pub fn is_code_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+/// Highlights one line of Markdown, returning one entry per character:
+/// headings, `**strong**`, `*emphasis*` and code, whole lines of it when
+/// `in_fence` or on the fence lines themselves.
+pub fn highlight_markdown(line: &str, in_fence: bool) -> Vec<Highlight> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = vec![Highlight::Normal; chars.len()];
+    if in_fence || is_code_fence(line) {
+        out.fill(Highlight::Code);
+        return out;
+    }
+    let hashes = run_end(&chars, 0, |c| c == '#');
+    if (1..=6).contains(&hashes) && chars.get(hashes).is_none_or(|&c| c == ' ') {
+        out.fill(Highlight::Heading);
+        return out;
+    }
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c == '\\' {
+            i += 2;
+        } else if c == '`' {
+            // A span opened by a run of backticks closes at the same run
+            let ticks = run_end(&chars, i, |c| c == '`') - i;
+            let fence = vec!['`'; ticks];
+            match find_seq(&chars, i + ticks, &fence) {
+                Some(end) => {
+                    out[i..end + ticks].fill(Highlight::Code);
+                    i = end + ticks;
+                }
+                None => i += ticks,
+            }
+        } else if (c == '*' || c == '_') && next == Some(c) {
+            match find_seq(&chars, i + 2, &[c, c]).filter(|&end| end > i + 2) {
+                Some(end) => {
+                    out[i..end + 2].fill(Highlight::Strong);
+                    i = end + 2;
+                }
+                None => i += 2,
+            }
+        } else if c == '*' || c == '_' {
+            // `_` inside a word, as in snake_case, is just a character
+            let opens = next.is_some_and(|next| !next.is_whitespace())
+                && !(c == '_' && i > 0 && chars[i - 1].is_alphanumeric());
+            match find_seq(&chars, i + 1, &[c]).filter(|_| opens) {
+                Some(end) if end > i + 1 => {
+                    out[i..=end].fill(Highlight::Emphasis);
+                    i = end + 1;
+                }
+                _ => i += 1,
+            }
+        } else {
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Index just past the closing `quote`, honouring backslash escapes.
+fn string_end(chars: &[char], mut i: usize, quote: char) -> usize {
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// Tells `'a'` and `'\n'` apart from lifetimes such as `'a`.
+fn is_char_literal(chars: &[char], i: usize) -> bool {
+    match chars.get(i + 1) {
+        Some('\\') => true,
+        Some(_) => chars.get(i + 2) == Some(&'\''),
+        None => false,
+    }
+}
+
+fn run_end(chars: &[char], mut i: usize, f: impl Fn(char) -> bool) -> usize {
+    while i < chars.len() && f(chars[i]) {
+        i += 1;
+    }
+    i
+}
+
+fn find_seq(chars: &[char], from: usize, seq: &[char]) -> Option<usize> {
+    (from..chars.len()).find(|&i| chars[i..].starts_with(seq))
+}
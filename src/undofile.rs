@@ -0,0 +1,119 @@
+//! Undo history kept on disk between sessions, under
+//! `~/.cache/text_editor/undo/`.
+//!
+//! Each file's history is stored next to a hash of the text it was saved
+//! with, and only loaded back if the file still hashes the same. Snapshots
+//! are written as differences from the next newer one, so a long history of
+//! small edits stays small. Only the undo stack is written: a session
+//! starts with nothing to redo.
+
+use crate::UndoState;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "text_editor undo 1";
+
+/// Writes the history of the file at `path`, just saved as `text`.
+pub fn save(path: &Path, text: &str, states: &[UndoState]) -> io::Result<()> {
+    let file = undo_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No cache directory"))?;
+    if states.is_empty() {
+        return match fs::remove_file(&file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let mut out = format!("{}\n{:016x}\n{}\n", HEADER, hash(text), states.len());
+    let mut newer = crate::split_lines(text);
+    for state in states.iter().rev() {
+        let content = state.content.to_vec();
+        let (prefix, suffix) = common_ends(&content, &newer);
+        let middle = &content[prefix..content.len() - suffix];
+        out.push_str(&format!(
+            "{} {} {} {} {}\n",
+            state.cursor_y,
+            state.cursor_x,
+            prefix,
+            suffix,
+            middle.len()
+        ));
+        for line in middle {
+            out.push_str(line);
+            out.push('\n');
+        }
+        newer = content;
+    }
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(file, out)
+}
+
+/// Reads back the history of the file at `path`, whose current text is
+/// `text`. Returns `None` if there is none or the file changed since.
+pub fn load(path: &Path, text: &str) -> Option<Vec<UndoState>> {
+    let stored = fs::read_to_string(undo_path(path)?).ok()?;
+    let mut lines = stored.split('\n');
+    if lines.next()? != HEADER || lines.next()? != format!("{:016x}", hash(text)) {
+        return None;
+    }
+    let count: usize = lines.next()?.parse().ok()?;
+    let mut states = Vec::with_capacity(count);
+    let mut newer = crate::split_lines(text);
+    for _ in 0..count {
+        let fields: Vec<usize> = lines
+            .next()?
+            .split(' ')
+            .map(|field| field.parse().ok())
+            .collect::<Option<_>>()?;
+        let &[cursor_y, cursor_x, prefix, suffix, len] = fields.as_slice() else {
+            return None;
+        };
+        if prefix + suffix > newer.len() {
+            return None;
+        }
+        let mut content = newer[..prefix].to_vec();
+        for _ in 0..len {
+            content.push(lines.next()?.to_string());
+        }
+        content.extend_from_slice(&newer[newer.len() - suffix..]);
+        if content.is_empty() || cursor_y >= content.len() {
+            return None;
+        }
+        newer = content.clone();
+        // Every state kept is from before the file was saved
+        states.push(UndoState { content: content.into(), cursor_x, cursor_y, modified: true });
+    }
+    states.reverse();
+    Some(states)
+}
+
+/// Where the history of `path` lives: its absolute path with each `/`
+/// turned into `%`, as vim names undo files.
+fn undo_path(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    let name = path.to_string_lossy().replace(['/', '\\'], "%");
+    Some(crate::cache_dir()?.join("undo").join(name))
+}
+
+/// Numbers of leading and trailing lines `a` and `b` share, not overlapping.
+fn common_ends(a: &[String], b: &[String]) -> (usize, usize) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let max_suffix = std::cmp::min(a.len(), b.len()) - prefix;
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take(max_suffix)
+        .take_while(|(x, y)| x == y)
+        .count();
+    (prefix, suffix)
+}
+
+/// 64-bit FNV-1a, which unlike std's hasher is stable across builds.
+fn hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,278 @@
+//! Just enough Unicode for cursor movement: grapheme cluster boundaries, so
+//! an accented letter or an emoji sequence moves and deletes as one unit,
+//! and display widths, so wide characters take their two terminal cells.
+//! Widths are those of whole clusters, the way a terminal draws them: an
+//! emoji sequence joined with ZWJ or a flag takes two cells in all, and a
+//! character made an emoji by a variation selector takes two.
+//!
+//! Positions are character indices into a line, the unit `cursor_x` counts
+//! in. The rules cover combining marks, variation selectors, emoji
+//! modifiers, zero-width joiner sequences and regional indicator (flag)
+//! pairs, an approximation of UAX #29 that handles what shows up in
+//! source code and prose.
+
+const ZWJ: char = '\u{200D}';
+
+/// Variation selector 16, asking for the emoji form of the character
+/// before it.
+const EMOJI_PRESENTATION: char = '\u{FE0F}';
+
+/// Whether `c` attaches to the character before it instead of starting a
+/// cluster of its own.
+pub fn is_extend(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}'
+        | '\u{0483}'..='\u{0489}'
+        | '\u{0591}'..='\u{05BD}'
+        | '\u{0610}'..='\u{061A}'
+        | '\u{064B}'..='\u{065F}'
+        | '\u{0670}'
+        | '\u{06D6}'..='\u{06DC}'
+        | '\u{06DF}'..='\u{06E4}'
+        | '\u{0900}'..='\u{0903}'
+        | '\u{093A}'..='\u{094F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200C}'..='\u{200D}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FE20}'..='\u{FE2F}'
+        | '\u{1F3FB}'..='\u{1F3FF}'
+        | '\u{E0020}'..='\u{E007F}'
+        | '\u{E0100}'..='\u{E01EF}')
+}
+
+/// Terminal cells `c` takes on its own: 2 for East Asian wide and emoji
+/// characters, 0 for marks that combine with the previous character, 1
+/// otherwise.
+pub fn width(c: char) -> usize {
+    if is_extend(c) {
+        return 0;
+    }
+    let wide = matches!(c,
+        '\u{1100}'..='\u{115F}'
+        | '\u{231A}'..='\u{231B}'
+        | '\u{2329}'..='\u{232A}'
+        | '\u{23E9}'..='\u{23EC}'
+        | '\u{23F0}'
+        | '\u{23F3}'
+        | '\u{25FD}'..='\u{25FE}'
+        | '\u{2614}'..='\u{2615}'
+        | '\u{2648}'..='\u{2653}'
+        | '\u{267F}'
+        | '\u{2693}'
+        | '\u{26A1}'
+        | '\u{26AA}'..='\u{26AB}'
+        | '\u{26BD}'..='\u{26BE}'
+        | '\u{26C4}'..='\u{26C5}'
+        | '\u{26CE}'
+        | '\u{26D4}'
+        | '\u{26EA}'
+        | '\u{26F2}'..='\u{26F3}'
+        | '\u{26F5}'
+        | '\u{26FA}'
+        | '\u{26FD}'
+        | '\u{2705}'
+        | '\u{270A}'..='\u{270B}'
+        | '\u{2728}'
+        | '\u{274C}'
+        | '\u{274E}'
+        | '\u{2753}'..='\u{2755}'
+        | '\u{2757}'
+        | '\u{2795}'..='\u{2797}'
+        | '\u{27B0}'
+        | '\u{27BF}'
+        | '\u{2B1B}'..='\u{2B1C}'
+        | '\u{2B50}'
+        | '\u{2B55}'
+        | '\u{2E80}'..='\u{303E}'
+        | '\u{3041}'..='\u{33FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{A000}'..='\u{A4CF}'
+        | '\u{A960}'..='\u{A97F}'
+        | '\u{AC00}'..='\u{D7A3}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FE10}'..='\u{FE19}'
+        | '\u{FE30}'..='\u{FE6F}'
+        | '\u{FF00}'..='\u{FF60}'
+        | '\u{FFE0}'..='\u{FFE6}'
+        | '\u{1F004}'
+        | '\u{1F0CF}'
+        | '\u{1F18E}'
+        | '\u{1F191}'..='\u{1F19A}'
+        | '\u{1F200}'..='\u{1F251}'
+        | '\u{1F300}'..='\u{1F64F}'
+        | '\u{1F680}'..='\u{1F6FF}'
+        | '\u{1F7E0}'..='\u{1F7EB}'
+        | '\u{1F90C}'..='\u{1F9FF}'
+        | '\u{1FA70}'..='\u{1FAFF}'
+        | '\u{20000}'..='\u{2FFFD}'
+        | '\u{30000}'..='\u{3FFFD}');
+    if wide {
+        2
+    } else {
+        1
+    }
+}
+
+/// The cells each character of `line` takes on screen. A cluster's width
+/// goes on its first character, and the rest take none.
+pub fn widths(line: &str) -> Vec<usize> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut widths = Vec::with_capacity(chars.len());
+    let mut x = 0;
+    while x < chars.len() {
+        let end = cluster_end(&chars, x);
+        widths.push(cluster_width(&chars[x..end]));
+        widths.resize(end, 0);
+        x = end;
+    }
+    widths
+}
+
+/// Terminal cells the grapheme cluster `cluster` takes.
+fn cluster_width(cluster: &[char]) -> usize {
+    let first = cluster[0];
+    let flag = cluster.len() > 1 && is_regional_indicator(first) && is_regional_indicator(cluster[1]);
+    if flag || (width(first) > 0 && cluster.contains(&EMOJI_PRESENTATION)) {
+        2
+    } else {
+        // Whatever follows, mark or joined emoji, is drawn over the first
+        width(first)
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+}
+
+/// The boundary after the cluster starting at character `x` of `line`.
+pub fn next_boundary(line: &str, x: usize) -> usize {
+    let chars: Vec<char> = line.chars().collect();
+    cluster_end(&chars, x)
+}
+
+/// The start of the cluster containing character `x` of `line`.
+pub fn cluster_start(line: &str, x: usize) -> usize {
+    let chars: Vec<char> = line.chars().collect();
+    let mut start = 0;
+    loop {
+        let next = cluster_end(&chars, start);
+        if next > x || next == start {
+            return start;
+        }
+        start = next;
+    }
+}
+
+/// The boundary before character `x` of `line`, or 0 at the start.
+pub fn prev_boundary(line: &str, x: usize) -> usize {
+    match x {
+        0 => 0,
+        x => cluster_start(line, x - 1),
+    }
+}
+
+/// Every boundary of `line`: the start of each cluster, then the end of
+/// the line, found in one pass.
+pub fn boundaries(line: &str) -> Vec<usize> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut boundaries = vec![0];
+    let mut x = 0;
+    while x < chars.len() {
+        x = cluster_end(&chars, x);
+        boundaries.push(x);
+    }
+    boundaries
+}
+
+/// The end of the cluster starting at `x` of `chars`.
+fn cluster_end(chars: &[char], x: usize) -> usize {
+    let len = chars.len();
+    if x >= len {
+        return len;
+    }
+    let mut end = x + 1;
+    // Two regional indicators make one flag
+    if is_regional_indicator(chars[x]) && end < len && is_regional_indicator(chars[end]) {
+        end += 1;
+    }
+    loop {
+        while end < len && is_extend(chars[end]) {
+            end += 1;
+        }
+        // A joiner glues the next character on, as in family emoji
+        if chars[end - 1] == ZWJ && end < len {
+            end += 1;
+        } else {
+            return end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_is_one_cluster() {
+        let line = "a\u{1F1FA}\u{1F1F8}b";
+        assert_eq!(next_boundary(line, 1), 3);
+        assert_eq!(cluster_start(line, 2), 1);
+        assert_eq!(prev_boundary(line, 3), 1);
+        assert_eq!(boundaries(line), vec![0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn two_flags_pair_up_in_order() {
+        let line = "\u{1F1FA}\u{1F1F8}\u{1F1EB}\u{1F1F7}";
+        assert_eq!(boundaries(line), vec![0, 2, 4]);
+        assert_eq!(cluster_start(line, 3), 2);
+    }
+
+    #[test]
+    fn combining_accent_joins_its_letter() {
+        let line = "caf\u{65}\u{301}!";
+        assert_eq!(next_boundary(line, 3), 5);
+        assert_eq!(cluster_start(line, 4), 3);
+        assert_eq!(prev_boundary(line, 5), 3);
+        assert_eq!(width('\u{301}'), 0);
+    }
+
+    #[test]
+    fn zwj_sequence_is_one_cluster() {
+        // Man, joiner, woman, joiner, girl
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let line = format!("x{}y", family);
+        assert_eq!(next_boundary(&line, 1), 6);
+        assert_eq!(cluster_start(&line, 4), 1);
+        assert_eq!(boundaries(&line), vec![0, 1, 6, 7]);
+    }
+
+    #[test]
+    fn wide_and_narrow_characters() {
+        assert_eq!(widths("a\u{4E2D}\u{2B50}\u{26A1}"), vec![1, 2, 2, 2]);
+        // Emoji that are text unless asked otherwise stay narrow
+        assert_eq!(widths("\u{2764}\u{2600}"), vec![1, 1]);
+    }
+
+    #[test]
+    fn clusters_take_the_width_of_what_is_drawn() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(widths(family), vec![2, 0, 0, 0, 0]);
+        // Red heart, and keycap one, in emoji form
+        assert_eq!(widths("\u{2764}\u{FE0F}"), vec![2, 0]);
+        assert_eq!(widths("1\u{FE0F}\u{20E3}"), vec![2, 0, 0]);
+        assert_eq!(widths("\u{1F1FA}\u{1F1F8}\u{1F1FA}"), vec![2, 0, 1]);
+        assert_eq!(widths("e\u{301}"), vec![1, 0]);
+        assert_eq!(widths("\u{1F44D}\u{1F3FD}"), vec![2, 0]);
+    }
+
+    #[test]
+    fn positions_past_the_end_stay_there() {
+        assert_eq!(next_boundary("ab", 2), 2);
+        assert_eq!(next_boundary("", 0), 0);
+        assert_eq!(prev_boundary("ab", 0), 0);
+    }
+}